@@ -0,0 +1,56 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use euclids_square::synthesizer::{fill_fixed, fill_float, Synth, SynthVoice, BLOCK_LEN, BUFFER_LEN, NUM_SOUNDS};
+use euclids_square::synthesizer::delay::{Delay, DELAY_LEN};
+use euclids_square::synthesizer::reverb::{Reverb, REVERB_LEN};
+
+// All voices playing while filling a half buffer, as in a DMA interrupt
+fn playing_synth<const NUM_VOICES: usize>() -> Synth<NUM_VOICES> {
+    let mut synth = Synth::new(array_init::array_init(|i| SynthVoice::new(i % NUM_SOUNDS)));
+    synth.apply_gates([127; NUM_VOICES]);
+    synth
+}
+
+fn bench_voices<const NUM_VOICES: usize>(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mixing");
+    let mut buffer = [0; BUFFER_LEN / 2];
+    group.bench_function(BenchmarkId::new("float", NUM_VOICES), |b| {
+        b.iter_batched_ref(playing_synth::<NUM_VOICES>, |synth| fill_float(&mut buffer, synth), criterion::BatchSize::SmallInput)
+    });
+    group.bench_function(BenchmarkId::new("fixed", NUM_VOICES), |b| {
+        b.iter_batched_ref(playing_synth::<NUM_VOICES>, |synth| fill_fixed(&mut buffer, synth), criterion::BatchSize::SmallInput)
+    });
+    // A sample at a time as before rendering blocks, for comparison
+    group.bench_function(BenchmarkId::new("float_per_sample", NUM_VOICES), |b| {
+        b.iter_batched_ref(playing_synth::<NUM_VOICES>, |synth| {
+            for val in buffer.iter_mut() {
+                let [left, right] = synth.step().map(|v| ((v + 1.) * 2047.5) as u32);
+                *val = (right << 16) + left;
+            }
+        }, criterion::BatchSize::SmallInput)
+    });
+    group.finish();
+}
+
+fn mixing(c: &mut Criterion) {
+    bench_voices::<3>(c);
+    bench_voices::<8>(c);
+}
+
+// The send effects on a half buffer, ringing on between iterations
+fn effects(c: &mut Criterion) {
+    let mut group = c.benchmark_group("effects");
+    let sends = [[0.25; 2]; BLOCK_LEN];
+    let sends_q15 = [[1 << 13; 2]; BLOCK_LEN];
+    let mut frames = [[0.; 2]; BLOCK_LEN];
+    let mut frames_q15 = [[0; 2]; BLOCK_LEN];
+    let mut delay = Delay::new(vec![[0; 2]; DELAY_LEN].leak());
+    group.bench_function(BenchmarkId::new("delay", "float"), |b| b.iter(|| delay.process(&sends, &mut frames)));
+    group.bench_function(BenchmarkId::new("delay", "fixed"), |b| b.iter(|| delay.process_q15(&sends_q15, &mut frames_q15)));
+    let mut reverb = Reverb::new(vec![0; REVERB_LEN].leak());
+    group.bench_function(BenchmarkId::new("reverb", "float"), |b| b.iter(|| reverb.process(&sends, &mut frames)));
+    group.bench_function(BenchmarkId::new("reverb", "fixed"), |b| b.iter(|| reverb.process_q15(&sends_q15, &mut frames_q15)));
+    group.finish();
+}
+
+criterion_group!(benches, mixing, effects);
+criterion_main!(benches);
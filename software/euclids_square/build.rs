@@ -0,0 +1,142 @@
+// Converts the sources in sounds/ to PCM and generates the SOUND_STORE table
+use std::env;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+// Emitted as SOUND_STORE_FREQ, which synthesizer.rs checks against its SAMPLE_FREQ
+const SAMPLE_FREQ: u32 = 44_100;
+
+// Samples below this level at the start and end are cut, about -48 dB
+const SILENCE_THRESHOLD: f32 = 0.004;
+
+const FADE_OUT_MS: u32 = 5;
+
+enum Format { U8, I16 }
+
+// Sound name, source file, maximum length in ms, sample format and General MIDI percussion note,
+// in order of SOUND_STORE. U8 takes half the flash of I16, which is fine for noisy sounds.
+const SOUNDS: [(&str, &str, u32, Format, u8); 3] = [
+    ("kick", "sounds/kick.wav", 300, Format::I16, 36),
+    ("snare", "sounds/snare.wav", 435, Format::I16, 38),
+    ("hihat", "sounds/hihat.ogg", 100, Format::U8, 42),
+];
+
+// Decodes to mono samples between -1 and 1 and the sample rate
+fn decode(path: &Path) -> (Vec<f32>, u32) {
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("wav") => decode_wav(path),
+        Some("ogg") => decode_ogg(path),
+        _ => panic!("Unsupported sound format: {}", path.display()),
+    }
+}
+
+fn decode_wav(path: &Path) -> (Vec<f32>, u32) {
+    let mut reader = hound::WavReader::open(path).unwrap();
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().map(Result::unwrap).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|s| s.unwrap() as f32 / scale).collect()
+        },
+    };
+    (downmix(&samples, spec.channels as usize), spec.sample_rate)
+}
+
+fn decode_ogg(path: &Path) -> (Vec<f32>, u32) {
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(BufReader::new(File::open(path).unwrap())).unwrap();
+    let channels = reader.ident_hdr.audio_channels as usize;
+    let mut samples = Vec::new();
+    while let Some(packet) = reader.read_dec_packet_itl().unwrap() {
+        samples.extend(packet.iter().map(|&s| s as f32 / 32768.));
+    }
+    (downmix(&samples, channels), reader.ident_hdr.audio_sample_rate)
+}
+
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    samples.chunks(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect()
+}
+
+// Linear interpolation without an anti-aliasing filter, so when downsampling the band
+// between SAMPLE_FREQ / 2 and the source Nyquist frequency folds back
+fn resample(samples: &[f32], rate: u32) -> Vec<f32> {
+    if rate == SAMPLE_FREQ || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let ratio = rate as f64 / SAMPLE_FREQ as f64;
+    let len = ((samples.len() - 1) as f64 / ratio) as usize + 1;
+    (0..len).map(|i| {
+        let pos = i as f64 * ratio;
+        let j = pos as usize;
+        let frac = (pos - j as f64) as f32;
+        let next = samples.get(j + 1).copied().unwrap_or(samples[j]);
+        samples[j] + (next - samples[j]) * frac
+    }).collect()
+}
+
+fn process(samples: &[f32], max_ms: u32) -> Vec<f32> {
+    let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    if peak == 0. {
+        return Vec::new();
+    }
+    let start = samples.iter().position(|s| s.abs() > SILENCE_THRESHOLD * peak).unwrap_or(0);
+    let max_len = (SAMPLE_FREQ * max_ms / 1000) as usize;
+    let samples = &samples[start..samples.len().min(start + max_len)];
+    let end = samples.iter().rposition(|s| s.abs() > SILENCE_THRESHOLD * peak).map_or(0, |i| i + 1);
+    let samples = &samples[..end];
+
+    // Normalise and fade out to avoid a click when the sound is cut
+    let fade_len = ((SAMPLE_FREQ * FADE_OUT_MS / 1000) as usize).min(samples.len());
+    let fade_start = samples.len() - fade_len;
+    samples.iter().enumerate().map(|(i, s)| {
+        let fade = if i < fade_start { 1. } else { (samples.len() - i) as f32 / fade_len as f32 };
+        s / peak * fade
+    }).collect()
+}
+
+// Unsigned 8 bit with 128 as zero, as read by SampleData
+fn to_u8(samples: &[f32]) -> Vec<u8> {
+    samples.iter().map(|s| (s * 127. + 128.).round().clamp(0., 255.) as u8).collect()
+}
+
+fn to_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter().map(|s| (s * 32767.).round().clamp(-32768., 32767.) as i16).collect()
+}
+
+// The u8 data is included as binary, i16 as array literal which guarantees its alignment
+fn sample_data(name: &str, format: &Format, samples: &[f32], out_dir: &str) -> String {
+    match format {
+        Format::U8 => {
+            fs::write(Path::new(out_dir).join(format!("{}.pcm", name)), to_u8(samples)).unwrap();
+            format!("SampleData::U8(include_bytes!(concat!(env!(\"OUT_DIR\"), \"/{}.pcm\")))", name)
+        },
+        Format::I16 => {
+            let values: Vec<String> = to_i16(samples).iter().map(i16::to_string).collect();
+            format!("SampleData::I16(&[{}])", values.join(", "))
+        },
+    }
+}
+
+fn main() {
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut table = String::new();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    for (name, source, max_ms, format, gm_note) in SOUNDS.iter() {
+        println!("cargo:rerun-if-changed={}", source);
+        let (samples, rate) = decode(Path::new(source));
+        let samples = process(&resample(&samples, rate), *max_ms);
+        assert!(!samples.is_empty(), "Sound is silent: {}", source);
+        table += &format!(
+            "    // {} samples\n    Sound {{ name: \"{}\", gm_note: {}, data: {} }},\n",
+            samples.len(), name, gm_note, sample_data(name, format, &samples, &out_dir),
+        );
+    }
+
+    let sounds = format!(
+        "pub const SOUND_STORE_FREQ: u32 = {};\n\npub const NUM_SAMPLES: usize = {};\n\npub static SOUND_STORE: [Sound; NUM_SAMPLES] = [\n{}];\n",
+        SAMPLE_FREQ, SOUNDS.len(), table,
+    );
+    fs::write(Path::new(&out_dir).join("sounds.rs"), sounds).unwrap();
+}
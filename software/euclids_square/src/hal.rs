@@ -0,0 +1,22 @@
+use crate::synthesizer::DmaState;
+
+pub mod mock;
+#[cfg(feature = "stm32")]
+pub mod stm32;
+
+pub trait InputPins {
+    // Switches A to D, true when pressed
+    fn switches(&self) -> [bool; 4];
+    // Pins A and B of encoders A to D, true when closed
+    fn encoders(&self) -> [(bool, bool); 4];
+}
+
+pub trait LedMatrix {
+    // Lights the two LEDs of one of the 8 multiplexed rows, see `leds::row_leds`
+    fn show_row(&mut self, row: u8, colors: [u32; 2]);
+}
+
+pub trait AudioSink {
+    // Acknowledges the pending transfer interrupt, reporting which half of the buffer can be filled
+    fn poll(&mut self) -> DmaState;
+}
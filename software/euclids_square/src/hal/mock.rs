@@ -0,0 +1,58 @@
+use crate::hal::{InputPins, LedMatrix, AudioSink};
+use crate::leds::{LedData, row_leds};
+use crate::synthesizer::DmaState;
+
+#[derive(Debug, Default, Clone)]
+pub struct MockInputPins {
+    pub switches: [bool; 4],
+    pub encoders: [(bool, bool); 4],
+}
+
+impl InputPins for MockInputPins {
+    fn switches(&self) -> [bool; 4] {
+        self.switches
+    }
+
+    fn encoders(&self) -> [(bool, bool); 4] {
+        self.encoders
+    }
+}
+
+// Collects the multiplexed rows into the frame they show
+#[derive(Debug, Default, Clone)]
+pub struct MockLedMatrix {
+    pub leds: LedData,
+    pub row: u8,
+}
+
+impl LedMatrix for MockLedMatrix {
+    fn show_row(&mut self, row: u8, colors: [u32; 2]) {
+        let (l1, l2) = row_leds(row);
+        self.leds[l1] = colors[0];
+        self.leds[l2] = colors[1];
+        self.row = row;
+    }
+}
+
+// Alternates between the two halves of the buffer, like the circular DMA transfer
+#[derive(Debug, Clone)]
+pub struct MockAudioSink {
+    next: DmaState,
+}
+
+impl Default for MockAudioSink {
+    fn default() -> Self {
+        Self { next: DmaState::Ht }
+    }
+}
+
+impl AudioSink for MockAudioSink {
+    fn poll(&mut self) -> DmaState {
+        let state = self.next;
+        self.next = match state {
+            DmaState::Ht => DmaState::Tc,
+            _ => DmaState::Ht,
+        };
+        state
+    }
+}
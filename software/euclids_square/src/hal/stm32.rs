@@ -0,0 +1,96 @@
+use stm32f7::stm32f7x2::{DMA1, GPIOC, TIM1, TIM5};
+use crate::hal::{InputPins, LedMatrix, AudioSink};
+use crate::synthesizer::DmaState;
+
+type GpioARead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpioa::_IDR>>;
+type GpioBRead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpiob::_IDR>>;
+type GpioCRead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpioh::_IDR>>;
+
+// Snapshot of the input data registers. Inputs have pull-ups, so are low when closed.
+pub struct GpioInputs {
+    pub gpioa: GpioARead,
+    pub gpiob: GpioBRead,
+    pub gpioc: GpioCRead,
+}
+
+impl InputPins for GpioInputs {
+    fn switches(&self) -> [bool; 4] {
+        [
+            self.gpioc.idr13().is_low(),
+            self.gpioc.idr10().is_low(),
+            self.gpioc.idr4().is_low(),
+            self.gpiob.idr14().is_low(),
+        ]
+    }
+
+    fn encoders(&self) -> [(bool, bool); 4] {
+        [
+            (self.gpioc.idr14().is_low(), self.gpioc.idr15().is_low()),
+            (self.gpioc.idr11().is_low(), self.gpioc.idr12().is_low()),
+            (self.gpioa.idr6().is_low(), self.gpioa.idr7().is_low()),
+            (self.gpiob.idr12().is_low(), self.gpiob.idr13().is_low()),
+        ]
+    }
+}
+
+pub struct PwmLeds<'a> {
+    pub gpioc: &'a GPIOC,
+    pub tim1: &'a TIM1,
+    pub tim5: &'a TIM5,
+}
+
+impl<'a> LedMatrix for PwmLeds<'a> {
+    fn show_row(&mut self, row: u8, colors: [u32; 2]) {
+        let (gpioc, tim1, tim5) = (self.gpioc, self.tim1, self.tim5);
+        let [c1, c2] = colors;
+        gpioc.odr.modify(|_, w| {
+            w.odr0().low()
+             .odr1().low()
+             .odr2().low()
+             .odr3().low()
+             .odr6().low()
+             .odr7().low()
+             .odr8().low()
+             .odr9().low()
+        });
+
+        tim5.ccr3.modify(|_, w| w.ccr().bits((c1 >> 16 & 0xFF) as u32));   // TIM5 CH3 = R1
+        tim5.ccr2.modify(|_, w| w.ccr().bits((c1 >> 8 & 0xFF) as u32 / 2)); // TIM5 CH2 = G1
+        tim5.ccr1.modify(|_, w| w.ccr().bits((c1 & 0xFF) as u32 / 2)); // TIM5 CH1 = B1
+        tim1.ccr1.modify(|_, w| w.ccr().bits((c2 >> 16 & 0xFF) as u16));   // TIM1 CH1 = R2
+        tim1.ccr2.modify(|_, w| w.ccr().bits((c2 >> 8 & 0xFF) as u16 / 2)); // TIM1 CH1 = G2
+        tim1.ccr3.modify(|_, w| w.ccr().bits((c2 & 0xFF) as u16 / 2)); // TIM1 CH1 = B2
+
+        tim1.egr.write(|w| w.ug().update());  // Update
+        tim5.egr.write(|w| w.ug().update());  // Update
+
+        gpioc.odr.modify(|_, w| {
+            w.odr0().bit(row == 0)
+             .odr1().bit(row == 1)
+             .odr2().bit(row == 2)
+             .odr3().bit(row == 3)
+             .odr6().bit(row == 4)
+             .odr7().bit(row == 5)
+             .odr8().bit(row == 6)
+             .odr9().bit(row == 7)
+        });
+    }
+}
+
+impl AudioSink for DMA1 {
+    fn poll(&mut self) -> DmaState {
+        let isr = self.hisr.read();
+        if isr.tcif5().is_complete() {
+            self.hifcr.write(|w| w.ctcif5().clear());
+            DmaState::Tc
+        } else if isr.htif5().is_half() {
+            self.hifcr.write(|w| w.chtif5().clear());
+            DmaState::Ht
+        } else if isr.teif5().is_error() {
+            self.hifcr.write(|w| w.cteif5().clear());
+            DmaState::Error
+        } else {
+            DmaState::Unknown
+        }
+    }
+}
@@ -1,9 +1,11 @@
 use stm32f7::stm32f7x2::Peripherals;
-use stm32f7::stm32f7x2::{DMA1, RCC};
+use stm32f7::stm32f7x2::{DMA1, RCC, TIM4};
 use stm32f7xx_hal::rcc::{RccExt, HSEClock, HSEClockMode, Clocks};
 use stm32f7xx_hal::prelude::*;
 
-use crate::synthesizer::SAMPLE_FREQ;
+use euclids_square::synthesizer::SAMPLE_FREQ;
+use euclids_square::sequencer::step_period_us;
+use euclids_square::ui::{tempo_to_bpm, DEFAULT_TEMPO};
 
 
 pub fn init_peripherals(device: &Peripherals) {
@@ -252,12 +254,16 @@ pub fn init_tim4(dp: &Peripherals) {
     rcc.apb1enr.modify(|_, w| w.tim4en().enabled());
 
     let tim4 = &dp.TIM4;
-    tim4.arr.modify(|_, w| w.arr().bits(1_000-1));
-    tim4.psc.modify(|_, w| w.psc().bits(21_600-1));
+    tim4.psc.modify(|_, w| w.psc().bits(1_080-1));  // 100 KHz
+    set_step_period(tim4, step_period_us(tempo_to_bpm(DEFAULT_TEMPO)));
     tim4.dier.modify(|_, w| w.uie().enabled());
     tim4.cr1.modify(|_, w| w.cen().enabled()); 
 }
 
+pub fn set_step_period(tim4: &TIM4, period_us: u32) {
+    tim4.arr.modify(|_, w| w.arr().bits((period_us / 10 - 1) as u16));
+}
+
 pub fn init_tim6(dp: &Peripherals) {
     // Timer for input polling
     // Base clock is 108 MHz
@@ -1,7 +1,9 @@
+use itertools::izip;
 use debouncr::{debounce_stateful_2, debounce_stateful_4, DebouncerStateful, Repeat4, Repeat2, Edge};
-type GpioARead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpioa::_IDR>>;
-type GpioBRead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpiob::_IDR>>;
-type GpioCRead = stm32f7::R<u32, stm32f7::Reg<u32, stm32f7::stm32f7x2::gpioh::_IDR>>;
+use crate::hal::InputPins;
+
+// Inputs are polled by TIM6 at 1 kHz
+pub const POLL_PERIOD_MS: u16 = 1;
 
 type SwitchDebouncer = DebouncerStateful<u8, Repeat4>;
 type RotDebouncer = DebouncerStateful<u8, Repeat2>;
@@ -12,7 +14,14 @@ pub enum RotDirection {
     Ccw,
 }
 
-type RotEvent = Option<RotDirection>;
+// A detent of the encoder, with the number of steps to take when turning fast
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RotTurn {
+    pub dir: RotDirection,
+    pub steps: u8,
+}
+
+type RotEvent = Option<RotTurn>;
 
 
 impl From<RotDirection> for isize {
@@ -24,6 +33,12 @@ impl From<RotDirection> for isize {
     }
 }
 
+impl From<RotTurn> for isize {
+    fn from(turn: RotTurn) -> Self {
+        isize::from(turn.dir) * turn.steps as isize
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct SwitchEvent {
     pub edge: Option<Edge>,
@@ -43,27 +58,108 @@ pub struct InputEvent {
     pub rot_d: RotEvent,
 }
 
+// Number of quadrature states between two detents of the encoder
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DetentMode {
+    Full,
+    Half,
+    Quarter,
+}
+
+impl DetentMode {
+    fn is_detent(self, state: u8) -> bool {
+        match self {
+            DetentMode::Full => state == 0b00,
+            DetentMode::Half => state == 0b00 || state == 0b11,
+            DetentMode::Quarter => true,
+        }
+    }
+
+    // Minimal net movement for a detent, which tolerates a missed transition
+    fn threshold(self) -> i8 {
+        match self {
+            DetentMode::Full => 2,
+            DetentMode::Half | DetentMode::Quarter => 1,
+        }
+    }
+}
+
+// Steps per detent when the previous detent was less than the given ms ago
+const ACCELERATION: [(u16, u8); 2] = [(30, 4), (60, 2)];
+
+// Position of each (pin A, pin B) state in the clockwise Gray code cycle 00 -> 10 -> 11 -> 01
+const QUADRATURE_POSITION: [i8; 4] = [0, 3, 1, 2];
+
 pub struct RotEnc {
     pin_a: RotDebouncer,
     pin_b: RotDebouncer,
+    mode: DetentMode,
+    state: u8,
+    movement: i8,
+    ms_since_detent: u16,
+    last_dir: Option<RotDirection>,
 }
 
 impl Default for RotEnc {
     fn default() -> Self {
+        Self::new(DetentMode::Full)
+    }
+}
+
+impl RotEnc {
+    pub fn new(mode: DetentMode) -> Self {
         Self {
             pin_a: debounce_stateful_2(false),
             pin_b: debounce_stateful_2(false),
+            mode,
+            state: 0b00,
+            movement: 0,
+            ms_since_detent: u16::MAX,
+            last_dir: None,
         }
     }
-}
 
-impl RotEnc {
     fn update(&mut self, pin_a: bool, pin_b: bool) -> RotEvent {
-        match (self.pin_a.update(pin_a), self.pin_b.update(pin_b)) {
-            (Some(Edge::Rising), _) if self.pin_b.is_low() => Some(RotDirection::Cw),
-            (Some(Edge::Rising), _) if self.pin_b.is_high() => Some(RotDirection::Ccw),
-            _ => None
+        self.pin_a.update(pin_a);
+        self.pin_b.update(pin_b);
+        self.ms_since_detent = self.ms_since_detent.saturating_add(POLL_PERIOD_MS);
+
+        let state = (self.pin_a.is_high() as u8) << 1 | self.pin_b.is_high() as u8;
+        if state == self.state {
+            return None;
+        }
+        let delta = (QUADRATURE_POSITION[state as usize] - QUADRATURE_POSITION[self.state as usize]).rem_euclid(4);
+        self.state = state;
+        // Skipping a state (delta 2) leaves the direction unknown, so is ignored
+        match delta {
+            1 => self.movement += 1,
+            3 => self.movement -= 1,
+            _ => (),
+        }
+        if !self.mode.is_detent(state) {
+            return None;
         }
+
+        let threshold = self.mode.threshold();
+        let dir = if self.movement >= threshold {
+            RotDirection::Cw
+        } else if self.movement <= -threshold {
+            RotDirection::Ccw
+        } else {
+            self.movement = 0;
+            return None;
+        };
+        self.movement = 0;
+        Some(self.turn(dir))
+    }
+
+    fn turn(&mut self, dir: RotDirection) -> RotTurn {
+        let steps = ACCELERATION.iter()
+            .find(|(ms, _)| self.last_dir == Some(dir) && self.ms_since_detent < *ms)
+            .map_or(1, |(_, steps)| *steps);
+        self.last_dir = Some(dir);
+        self.ms_since_detent = 0;
+        RotTurn { dir, steps }
     }
 }
 
@@ -103,16 +199,373 @@ pub struct Inputs {
 }
 
 impl Inputs {
-    pub fn update(&mut self, gpioa: GpioARead, gpiob: GpioBRead, gpioc: GpioCRead) -> InputEvent {
+    pub fn update<P: InputPins>(&mut self, pins: &P) -> InputEvent {
+        let [switch_a, switch_b, switch_c, switch_d] = pins.switches();
+        let [rot_a, rot_b, rot_c, rot_d] = pins.encoders();
         InputEvent {
-            switch_a: self.switch_a.update(gpioc.idr13().is_low()),
-            switch_b: self.switch_b.update(gpioc.idr10().is_low()),
-            switch_c: self.switch_c.update(gpioc.idr4().is_low()),
-            switch_d: self.switch_d.update(gpiob.idr14().is_low()),
-            rot_a: self.rot_a.update(gpioc.idr14().is_low(), gpioc.idr15().is_low()),
-            rot_b: self.rot_b.update(gpioc.idr11().is_low(), gpioc.idr12().is_low()),
-            rot_c: self.rot_c.update(gpioa.idr6().is_low(), gpioa.idr7().is_low()),
-            rot_d: self.rot_d.update(gpiob.idr12().is_low(), gpiob.idr13().is_low()),
+            switch_a: self.switch_a.update(switch_a),
+            switch_b: self.switch_b.update(switch_b),
+            switch_c: self.switch_c.update(switch_c),
+            switch_d: self.switch_d.update(switch_d),
+            rot_a: self.rot_a.update(rot_a.0, rot_a.1),
+            rot_b: self.rot_b.update(rot_b.0, rot_b.1),
+            rot_c: self.rot_c.update(rot_c.0, rot_c.1),
+            rot_d: self.rot_d.update(rot_d.0, rot_d.1),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Gesture {
+    Click,
+    DoubleClick,
+    LongPress,
+    // Reported on release after a long press, with the total press duration in ms
+    Held(u16),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GestureConfig {
+    pub long_press_ms: u16,
+    // Without a double click window, clicks are reported immediately on release
+    pub double_click_ms: Option<u16>,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            long_press_ms: 500,
+            double_click_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum GestureState {
+    Idle,
+    Pressed { ms: u16, second: bool },
+    WaitSecond { ms: u16 },
+}
+
+#[derive(Debug, Clone)]
+pub struct GestureRecognizer {
+    config: GestureConfig,
+    state: GestureState,
+    long_pressed: bool,
+    cancelled: bool,
+}
+
+impl Default for GestureRecognizer {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
+}
+
+impl GestureRecognizer {
+    pub fn new(config: GestureConfig) -> Self {
+        Self {
+            config,
+            state: GestureState::Idle,
+            long_pressed: false,
+            cancelled: false,
+        }
+    }
+
+    pub fn update(&mut self, event: SwitchEvent) -> Option<Gesture> {
+        match (self.state, event.edge) {
+            (GestureState::Idle, Some(Edge::Rising)) => {
+                self.press(false);
+                None
+            },
+            (GestureState::WaitSecond { .. }, Some(Edge::Rising)) => {
+                self.press(true);
+                None
+            },
+            (GestureState::Pressed { ms, second }, None) => {
+                let ms = ms.saturating_add(POLL_PERIOD_MS);
+                self.state = GestureState::Pressed { ms, second };
+                if ms >= self.config.long_press_ms && !self.long_pressed && !self.cancelled {
+                    self.long_pressed = true;
+                    Some(Gesture::LongPress)
+                } else {
+                    None
+                }
+            },
+            (GestureState::Pressed { ms, second }, Some(Edge::Falling)) => {
+                self.state = GestureState::Idle;
+                if self.cancelled {
+                    None
+                } else if self.long_pressed {
+                    Some(Gesture::Held(ms))
+                } else if second {
+                    Some(Gesture::DoubleClick)
+                } else if self.config.double_click_ms.is_some() {
+                    self.state = GestureState::WaitSecond { ms: 0 };
+                    None
+                } else {
+                    Some(Gesture::Click)
+                }
+            },
+            (GestureState::WaitSecond { ms }, None) => {
+                let ms = ms.saturating_add(POLL_PERIOD_MS);
+                if ms >= self.config.double_click_ms.unwrap_or(0) {
+                    self.state = GestureState::Idle;
+                    Some(Gesture::Click)
+                } else {
+                    self.state = GestureState::WaitSecond { ms };
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+
+    fn press(&mut self, second: bool) {
+        self.state = GestureState::Pressed { ms: 0, second };
+        self.long_pressed = false;
+        self.cancelled = false;
+    }
+
+    // Suppresses all gestures of the current press, e.g. when the switch is used as modifier
+    pub fn cancel(&mut self) {
+        if let GestureState::Pressed { .. } = self.state {
+            self.cancelled = true;
+        }
+    }
+
+    pub fn is_held(&self) -> bool {
+        matches!(self.state, GestureState::Pressed { .. })
+    }
+}
+
+// Bit masks of the switches in a chord
+pub const CHORD_A: u8 = 1 << 0;
+pub const CHORD_B: u8 = 1 << 1;
+pub const CHORD_C: u8 = 1 << 2;
+pub const CHORD_D: u8 = 1 << 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct GestureEvent {
+    pub switches: [Option<Gesture>; 4],
+    // Switches that were held together, reported once all of them are released
+    pub chord: Option<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Gestures {
+    switches: [GestureRecognizer; 4],
+    chord: u8,
+    chord_cancelled: bool,
+}
+
+impl Gestures {
+    pub fn new(configs: [GestureConfig; 4]) -> Self {
+        Self {
+            switches: [
+                GestureRecognizer::new(configs[0]),
+                GestureRecognizer::new(configs[1]),
+                GestureRecognizer::new(configs[2]),
+                GestureRecognizer::new(configs[3]),
+            ],
+            chord: 0,
+            chord_cancelled: false,
+        }
+    }
+
+    pub fn update(&mut self, input: &InputEvent) -> GestureEvent {
+        let events = [input.switch_a, input.switch_b, input.switch_c, input.switch_d];
+        let mut gesture_event = GestureEvent::default();
+        for (gesture, recognizer, event) in izip!(gesture_event.switches.iter_mut(), self.switches.iter_mut(), &events) {
+            *gesture = recognizer.update(*event);
+        }
+
+        let pressed = events.iter()
+            .enumerate()
+            .filter(|(_, event)| event.is_high)
+            .fold(0u8, |mask, (i, _)| mask | 1 << i);
+        if pressed.count_ones() >= 2 {
+            // Switches in a chord don't report their own gestures
+            self.chord |= pressed;
+            for (i, recognizer) in self.switches.iter_mut().enumerate() {
+                if self.chord & 1 << i != 0 {
+                    recognizer.cancel();
+                }
+            }
+        } else if pressed == 0 && self.chord != 0 {
+            if !self.chord_cancelled {
+                gesture_event.chord = Some(self.chord);
+            }
+            self.chord = 0;
+            self.chord_cancelled = false;
+        }
+        gesture_event
+    }
+
+    pub fn cancel(&mut self, switch: usize) {
+        self.switches[switch].cancel();
+        if self.chord & 1 << switch != 0 {
+            self.chord_cancelled = true;
         }
     }
-}
\ No newline at end of file
+
+    pub fn is_held(&self, switch: usize) -> bool {
+        self.switches[switch].is_held()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn switch_event(edge: Option<Edge>, is_high: bool) -> SwitchEvent {
+        SwitchEvent { edge, is_high, is_low: !is_high }
+    }
+
+    // Feeds a switch held down for `down` ms followed by `up` ms of release
+    fn press(recognizer: &mut GestureRecognizer, down: u16, up: u16) -> std::vec::Vec<Gesture> {
+        let mut events = vec![switch_event(Some(Edge::Rising), true)];
+        events.extend((1..down).map(|_| switch_event(None, true)));
+        events.push(switch_event(Some(Edge::Falling), false));
+        events.extend((1..up).map(|_| switch_event(None, false)));
+        events.into_iter().filter_map(|event| recognizer.update(event)).collect()
+    }
+
+    fn double_click_config() -> GestureConfig {
+        GestureConfig { long_press_ms: 500, double_click_ms: Some(250) }
+    }
+
+    #[test]
+    fn click() {
+        let mut recognizer = GestureRecognizer::default();
+        assert_eq!(press(&mut recognizer, 50, 10), vec![Gesture::Click]);
+    }
+
+    #[test]
+    fn click_waits_for_double_click_window() {
+        let mut recognizer = GestureRecognizer::new(double_click_config());
+        assert_eq!(press(&mut recognizer, 50, 200), vec![]);
+        assert_eq!(press(&mut recognizer, 0, 0), vec![Gesture::DoubleClick]);
+
+        assert_eq!(press(&mut recognizer, 50, 300), vec![Gesture::Click]);
+        assert_eq!(press(&mut recognizer, 50, 300), vec![Gesture::Click]);
+    }
+
+    #[test]
+    fn long_press() {
+        let mut recognizer = GestureRecognizer::new(double_click_config());
+        assert_eq!(press(&mut recognizer, 800, 300), vec![Gesture::LongPress, Gesture::Held(799)]);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut recognizer = GestureRecognizer::default();
+        recognizer.update(switch_event(Some(Edge::Rising), true));
+        recognizer.cancel();
+        assert_eq!(press(&mut recognizer, 0, 0), vec![]);
+        assert!(!recognizer.is_held());
+        assert_eq!(press(&mut recognizer, 800, 0), vec![Gesture::LongPress, Gesture::Held(799)]);
+    }
+
+    #[test]
+    fn debounced_click() {
+        let mut switch = Switch::default();
+        let mut recognizer = GestureRecognizer::default();
+        let levels = [0, 1, 0, 1, 1, 1, 1, 1, 1, 0, 1, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        let gestures: std::vec::Vec<_> = levels.iter()
+            .filter_map(|&level| recognizer.update(switch.update(level == 1)))
+            .collect();
+        assert_eq!(gestures, vec![Gesture::Click]);
+    }
+
+    // Feeds (pin A, pin B) states, each held for `hold` polls
+    fn turn(rot_enc: &mut RotEnc, states: &[(u8, u8)], hold: usize) -> std::vec::Vec<RotTurn> {
+        states.iter()
+            .flat_map(|&state| std::iter::repeat_n(state, hold))
+            .filter_map(|(a, b)| rot_enc.update(a == 1, b == 1))
+            .collect()
+    }
+
+    const CW: [(u8, u8); 4] = [(1, 0), (1, 1), (0, 1), (0, 0)];
+    const CCW: [(u8, u8); 4] = [(0, 1), (1, 1), (1, 0), (0, 0)];
+
+    fn slow(dir: RotDirection) -> RotTurn {
+        RotTurn { dir, steps: 1 }
+    }
+
+    #[test]
+    fn quadrature_directions() {
+        let mut rot_enc = RotEnc::default();
+        assert_eq!(turn(&mut rot_enc, &CW, 50), vec![slow(RotDirection::Cw)]);
+        assert_eq!(turn(&mut rot_enc, &CW, 50), vec![slow(RotDirection::Cw)]);
+        assert_eq!(turn(&mut rot_enc, &CCW, 50), vec![slow(RotDirection::Ccw)]);
+    }
+
+    #[test]
+    fn quadrature_half_detents() {
+        let mut rot_enc = RotEnc::new(DetentMode::Half);
+        let expected = vec![slow(RotDirection::Cw), slow(RotDirection::Cw)];
+        assert_eq!(turn(&mut rot_enc, &CW, 50), expected);
+    }
+
+    #[test]
+    fn quadrature_bounce() {
+        let mut rot_enc = RotEnc::default();
+        // Contact bounce shorter than the debounce time is filtered out
+        let bouncy = [(1, 0), (0, 0), (1, 0), (1, 0), (1, 1), (1, 0), (1, 1), (1, 1), (0, 1), (0, 1), (0, 0), (0, 0)];
+        assert_eq!(turn(&mut rot_enc, &bouncy, 1), vec![slow(RotDirection::Cw)]);
+        // Longer bounces are decoded as small movements back and forth
+        let bouncy = [(0, 0), (1, 0), (0, 0), (1, 0), (0, 0), (1, 0), (1, 1), (0, 1), (1, 1), (0, 1), (0, 0), (0, 1), (0, 0)];
+        assert_eq!(turn(&mut rot_enc, &bouncy, 20), vec![slow(RotDirection::Cw)]);
+    }
+
+    #[test]
+    fn quadrature_reversal() {
+        let mut rot_enc = RotEnc::default();
+        // Turning half way and back is no detent
+        let back = [(1, 0), (1, 1), (1, 0), (0, 0)];
+        assert_eq!(turn(&mut rot_enc, &back, 50), vec![]);
+        let reversal = [(1, 0), (1, 1), (0, 1), (0, 0), (0, 1), (1, 1), (1, 0), (0, 0)];
+        let expected = vec![slow(RotDirection::Cw), slow(RotDirection::Ccw)];
+        assert_eq!(turn(&mut rot_enc, &reversal, 50), expected);
+    }
+
+    #[test]
+    fn acceleration() {
+        let mut rot_enc = RotEnc::default();
+        let fast: std::vec::Vec<_> = CW.iter().cycle().take(12).cloned().collect();
+        let steps: std::vec::Vec<_> = turn(&mut rot_enc, &fast, 4).iter().map(|turn| turn.steps).collect();
+        assert_eq!(steps, vec![1, 4, 4]);
+        let steps: std::vec::Vec<_> = turn(&mut rot_enc, &CW, 10).iter().map(|turn| turn.steps).collect();
+        assert_eq!(steps, vec![2]);
+        // Reversing direction does not accelerate
+        assert_eq!(turn(&mut rot_enc, &CCW, 4), vec![slow(RotDirection::Ccw)]);
+    }
+
+    #[test]
+    fn chord() {
+        let mut gestures = Gestures::default();
+        let idle = switch_event(None, false);
+        let mut input = InputEvent {
+            switch_a: idle, switch_b: idle, switch_c: idle, switch_d: idle,
+            rot_a: None, rot_b: None, rot_c: None, rot_d: None,
+        };
+        let mut events = vec![];
+
+        input.switch_a = switch_event(Some(Edge::Rising), true);
+        events.push(gestures.update(&input));
+        input.switch_a = switch_event(None, true);
+        input.switch_d = switch_event(Some(Edge::Rising), true);
+        events.push(gestures.update(&input));
+        input.switch_d = switch_event(None, true);
+        events.push(gestures.update(&input));
+        input.switch_a = switch_event(Some(Edge::Falling), false);
+        events.push(gestures.update(&input));
+        input.switch_a = idle;
+        input.switch_d = switch_event(Some(Edge::Falling), false);
+        events.push(gestures.update(&input));
+
+        let chords: std::vec::Vec<_> = events.iter().filter_map(|event| event.chord).collect();
+        assert_eq!(chords, vec![CHORD_A | CHORD_D]);
+        assert!(events.iter().all(|event| event.switches == [None; 4]));
+    }
+}
@@ -1,4 +1,4 @@
-use stm32f7::stm32f7x2::{GPIOC, TIM1, TIM5};
+use crate::hal::LedMatrix;
 
 pub type LedData = [u32; 16];
 
@@ -6,19 +6,9 @@ pub fn layer_color(i: usize, val: u8) -> u32 {
     (val as u32) << ((i % 3) * 8)
 }
 
-pub fn show_leds_pwm(gpioc: &GPIOC, tim1: &TIM1, tim5: &TIM5, data: &LedData, step: u8) {
-    gpioc.odr.modify(|_, w| {
-        w.odr0().low()
-         .odr1().low()
-         .odr2().low()
-         .odr3().low()
-         .odr6().low()
-         .odr7().low()
-         .odr8().low()
-         .odr9().low()
-    });
-
-    let (l1, l2) = match step {
+// The two LEDs lit together in each multiplexed row
+pub fn row_leds(row: u8) -> (usize, usize) {
+    match row {
         0 => (0, 7),
         1 => (1, 6),
         2 => (2, 5),
@@ -28,26 +18,26 @@ pub fn show_leds_pwm(gpioc: &GPIOC, tim1: &TIM1, tim5: &TIM5, data: &LedData, st
         6 => (13, 10),
         7 => (12, 11),
         _ => panic!()
-    };
+    }
+}
 
-    tim5.ccr3.modify(|_, w| w.ccr().bits((data[l1] >> 16 & 0xFF) as u32));   // TIM5 CH3 = R1
-    tim5.ccr2.modify(|_, w| w.ccr().bits((data[l1] >> 8 & 0xFF) as u32 / 2)); // TIM5 CH2 = G1
-    tim5.ccr1.modify(|_, w| w.ccr().bits((data[l1] & 0xFF) as u32 / 2)); // TIM5 CH1 = B1
-    tim1.ccr1.modify(|_, w| w.ccr().bits((data[l2] >> 16 & 0xFF) as u16));   // TIM1 CH1 = R2
-    tim1.ccr2.modify(|_, w| w.ccr().bits((data[l2] >> 8 & 0xFF) as u16 / 2)); // TIM1 CH1 = G2
-    tim1.ccr3.modify(|_, w| w.ccr().bits((data[l2] & 0xFF) as u16 / 2)); // TIM1 CH1 = B2
+pub fn show_leds<M: LedMatrix>(matrix: &mut M, data: &LedData, step: u8) {
+    let (l1, l2) = row_leds(step);
+    matrix.show_row(step, [data[l1], data[l2]]);
+}
 
-    tim1.egr.write(|w| w.ug().update());  // Update
-    tim5.egr.write(|w| w.ug().update());  // Update
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::mock::MockLedMatrix;
 
-    gpioc.odr.modify(|_, w| {
-        w.odr0().bit(step == 0)
-         .odr1().bit(step == 1)
-         .odr2().bit(step == 2)
-         .odr3().bit(step == 3)
-         .odr6().bit(step == 4)
-         .odr7().bit(step == 5)
-         .odr8().bit(step == 6)
-         .odr9().bit(step == 7)
-    });
-}
\ No newline at end of file
+    #[test]
+    fn multiplexing_shows_all_leds() {
+        let data: LedData = array_init::array_init(|i| i as u32 + 1);
+        let mut matrix = MockLedMatrix::default();
+        for step in 0..8 {
+            show_leds(&mut matrix, &data, step);
+        }
+        assert_eq!(matrix.leds, data);
+    }
+}
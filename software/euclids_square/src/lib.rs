@@ -0,0 +1,10 @@
+#![cfg_attr(not(test), no_std)]
+
+pub mod synthesizer;
+pub mod sequencer;
+pub mod leds;
+pub mod inputs;
+pub mod ui;
+pub mod view;
+pub mod hal;
+pub mod midi;
@@ -6,28 +6,30 @@ extern crate stm32f7;
 
 extern crate panic_itm;
 use rtic::app;
-use cortex_m::{iprintln, peripheral::ITM};
+use cortex_m::{iprintln, peripheral::{DWT, ITM}};
 use stm32f7::stm32f7x2::Peripherals;
 use stm32f7::stm32f7x2::{GPIOA, GPIOB, GPIOC, GPIOE, DMA1, DAC, TIM1, TIM3, TIM4, TIM5, TIM6};
-use core::mem;
+use core::{cmp, mem};
+use arrayvec::ArrayVec;
 
-mod synthesizer;
-mod sequencer;
-mod leds;
 mod init_peripherals;
-mod inputs;
-mod ui;
-mod view;
-
-use synthesizer::{BUFFER_LEN, dma_handler, DmaState, Synth, SynthVoice};
-use leds::{show_leds_pwm, LedData};
-use init_peripherals::{init_peripherals, init_dma1, init_clock};
-use sequencer::Sequencer;
-use inputs::{Inputs};
-use ui::{UiState, OutputEvent};
-use view::render;
+
+use euclids_square::synthesizer::{BUFFER_LEN, dma_handler, DmaState, Synth, SynthVoice};
+use euclids_square::leds::{show_leds, LedData};
+use euclids_square::hal::stm32::{GpioInputs, PwmLeds};
+use init_peripherals::{init_peripherals, init_dma1, init_clock, set_step_period};
+use euclids_square::sequencer::{Sequencer, step_period_us};
+use euclids_square::inputs::{Inputs};
+use euclids_square::synthesizer::delay::{Delay, DelayLine, DELAY_LEN};
+use euclids_square::synthesizer::reverb::{Reverb, ReverbLine, REVERB_LEN};
+use euclids_square::ui::{UiState, OutputEvent, volume_to_gain, pan_to_position, trim_to_fraction, tempo_to_bpm, cutoff_to_hz, resonance_to_amount};
+use euclids_square::ui::{send_to_gain, feedback_to_amount, damping_to_amount, decay_to_amount, depth_to_amount, DEFAULT_TEMPO};
+use euclids_square::ui::{compress_to_threshold, crush_to_bits, drive_to_gain};
+use euclids_square::view::render;
+use euclids_square::midi::{self, write_smf, SmfFormat};
 
 const NUM_LAYERS: usize = 3;
+const MIDI_DUMP_LEN: usize = midi::MAX_BAR_LEN;
 
 // We need to pass monotonic = rtic::cyccnt::CYCCNT to use schedule feature fo RTIC
 #[app(device = stm32f7::stm32f7x2, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
@@ -51,10 +53,15 @@ const APP: () = {
         sequencer: Sequencer<NUM_LAYERS, 16>,
         inputs: Inputs,
         ui: UiState<NUM_LAYERS>,
+        // Most cycles the audio callback took so far
+        max_cycles: u32,
     }
 
     #[init(spawn = [init_dma1_task])]
     fn init(cx: init::Context) -> init::LateResources {
+        // 153 KB of the SRAM, outside of the resources so they aren't moved
+        static mut DELAY_LINE: DelayLine = [[0; 2]; DELAY_LEN];
+        static mut REVERB_LINE: ReverbLine = [0; REVERB_LEN];
         let mut core = cx.core;
         let mut itm = core.ITM;
         core.DWT.enable_cycle_counter();
@@ -66,16 +73,34 @@ const APP: () = {
 
         iprintln!(&mut itm.stim[0], "Hello, Euclid!");
         
-        let synth = Synth { voices: [SynthVoice::new(0), SynthVoice::new(1), SynthVoice::new(2)]};
+        let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(1), SynthVoice::new(2)]);
+        synth.delay = Delay::new(DELAY_LINE);
+        synth.delay.set_tempo(tempo_to_bpm(DEFAULT_TEMPO));
+        synth.reverb = Reverb::new(REVERB_LINE);
         let mut sequencer: Sequencer<3, 16> = Default::default();
-        sequencer.set_sequence(0, 16, 1, 0);
-        sequencer.set_sequence(1, 16, 0, 0);
-        sequencer.set_sequence(2, 16, 0, 0);
+        sequencer.set_sequence(0, 16, 1, 0, 0);
+        sequencer.set_sequence(1, 16, 0, 0, 0);
+        sequencer.set_sequence(2, 16, 0, 0, 0);
 
         iprintln!(&mut itm.stim[0], "{:?}", sequencer);
 
         let inputs: Inputs = Default::default();
         let ui: UiState<NUM_LAYERS> = Default::default();
+        // The effects start as the UI shows them
+        synth.delay.set_division(ui.delay.division);
+        synth.delay.feedback = feedback_to_amount(ui.delay.feedback);
+        synth.delay.damping = damping_to_amount(ui.delay.damping);
+        synth.reverb.decay = decay_to_amount(ui.reverb.decay);
+        synth.reverb.damping = damping_to_amount(ui.reverb.damping);
+        synth.sidechain.depth = depth_to_amount(ui.sidechain.depth);
+        synth.sidechain.release_ms = ui.sidechain.release_ms as f32;
+        synth.master.compressor.bypass = ui.master.compress == 0;
+        synth.master.compressor.threshold = compress_to_threshold(ui.master.compress);
+        synth.master.bitcrusher.bypass = ui.master.crush == 0 && ui.master.downsample == 1;
+        synth.master.bitcrusher.set_bits(crush_to_bits(ui.master.crush));
+        synth.master.bitcrusher.downsample = ui.master.downsample as u32;
+        synth.master.soft_clip.bypass = ui.master.drive == 0;
+        synth.master.soft_clip.drive = drive_to_gain(ui.master.drive);
 
         init::LateResources {
             auido_buffer: [0; BUFFER_LEN],
@@ -96,6 +121,7 @@ const APP: () = {
             sequencer,
             inputs,
             ui,
+            max_cycles: 0,
         }
     }
 
@@ -108,9 +134,14 @@ const APP: () = {
         dac.cr.modify(|_, w| w.dmaen1().enabled());
     }
 
-    #[task(binds = DMA1_STREAM5, resources = [dma1, auido_buffer, synth], priority=1)]
-    fn dma1_stream5(mut cx: dma1_stream5::Context) {
-        let state = dma_handler(cx.resources.dma1, &mut cx.resources.auido_buffer, &mut cx.resources.synth);
+    #[task(binds = DMA1_STREAM5, resources = [dma1, auido_buffer, synth, max_cycles], priority=1)]
+    fn dma1_stream5(cx: dma1_stream5::Context) {
+        let start = DWT::cycle_count();
+        let state = dma_handler(cx.resources.dma1, cx.resources.auido_buffer, cx.resources.synth);
+        // Rendering a half buffer has about 2.5M cycles at 216 MHz, the UI prints each new maximum
+        let cycles = DWT::cycle_count().wrapping_sub(start);
+        let max_cycles = cx.resources.max_cycles;
+        *max_cycles = cmp::max(*max_cycles, cycles);
         match state {
             DmaState::Error =>  panic!("DMA error"),
             DmaState::Unknown =>  panic!("Unkonwn DMA state"),
@@ -128,7 +159,7 @@ const APP: () = {
         let tim5 = cx.resources.tim5;
         let led_data = cx.resources.led_data;
         tim3.sr.modify(|_, w| w.uif().clear_bit());
-        show_leds_pwm(gpioc, tim1, tim5, &led_data, *STEP);
+        show_leds(&mut PwmLeds { gpioc, tim1, tim5 }, &led_data, *STEP);
         *STEP = (*STEP + 1) % 8;
     }
 
@@ -138,13 +169,16 @@ const APP: () = {
         let tim4 = cx.resources.tim4;
         tim4.sr.modify(|_, w| w.uif().clear_bit());
 
-        let gates = cx.resources.sequencer.step();
-        cx.resources.synth.apply_gates(gates);
+        let velocities = cx.resources.sequencer.step();
+        cx.resources.synth.apply_gates(velocities);
     }
 
     // User interface
-    #[task(binds = TIM6_DAC, resources=[tim6, inputs, gpioa, gpiob, gpioc, itm, ui, led_data, sequencer, tim4], priority=1)]
+    #[task(binds = TIM6_DAC, resources=[tim6, inputs, gpioa, gpiob, gpioc, itm, ui, led_data, sequencer, synth, tim4, max_cycles], priority=1)]
     fn tim6(mut cx: tim6::Context) {
+        static mut MIDI_DUMP: ArrayVec<u8, MIDI_DUMP_LEN> = ArrayVec::new_const();
+        static mut MIDI_SENT: usize = 0;
+        static mut PRINTED_CYCLES: u32 = 0;
         let tim4 = cx.resources.tim4;
         let tim6 = cx.resources.tim6;
         let inputs = cx.resources.inputs;
@@ -152,31 +186,118 @@ const APP: () = {
         let itm = cx.resources.itm;
         let ui = cx.resources.ui;
         let sequencer = cx.resources.sequencer;
+        let synth = cx.resources.synth;
 
-        let gpioa_read = cx.resources.gpioa.idr.read();
-        let gpiob_read = cx.resources.gpiob.idr.read();
-        let gpioc_read = cx.resources.gpioc.lock(|gpioc| gpioc.idr.read());
-        let input_event = inputs.update(gpioa_read, gpiob_read, gpioc_read);
+        let pins = GpioInputs {
+            gpioa: cx.resources.gpioa.idr.read(),
+            gpiob: cx.resources.gpiob.idr.read(),
+            gpioc: cx.resources.gpioc.lock(|gpioc| gpioc.idr.read()),
+        };
+        let input_event = inputs.update(&pins);
         let output_events = ui.update(input_event);
 
         for output_event in output_events {
             match output_event {
                 OutputEvent::LayerUpdate (layer, layer_state) => {
                     iprintln!(&mut itm.stim[0], "{} {:?}", layer, layer_state);
-                    sequencer.set_sequence(layer, layer_state.length, layer_state.hits, layer_state.shift);
-                    // TODO set volume
+                    sequencer.set_sequence(layer, layer_state.length, layer_state.hits, layer_state.shift, layer_state.accents);
+                },
+                OutputEvent::SoundUpdate (layer, sound) => {
+                    synth.voices[layer].set_sound(sound);
+                },
+                OutputEvent::VolumeUpdate (layer, volume) => {
+                    synth.voices[layer].set_volume(volume_to_gain(volume));
+                },
+                OutputEvent::PanUpdate (layer, pan) => {
+                    synth.voices[layer].set_pan(pan_to_position(pan));
+                },
+                OutputEvent::RetriggerUpdate (layer, retrigger) => {
+                    synth.voices[layer].set_retrigger(retrigger);
+                },
+                OutputEvent::TuneUpdate (layer, tune) => {
+                    synth.voices[layer].set_tune(tune as f32);
+                },
+                OutputEvent::ReverseUpdate (layer, reverse) => {
+                    synth.voices[layer].set_reverse(reverse);
+                },
+                OutputEvent::TrimUpdate (layer, start, end) => {
+                    synth.voices[layer].set_trim(trim_to_fraction(start), trim_to_fraction(end));
+                },
+                OutputEvent::EnvelopeUpdate (layer, attack_ms, decay_ms) => {
+                    synth.voices[layer].set_envelope(attack_ms, decay_ms);
+                },
+                OutputEvent::FilterUpdate (layer, mode, cutoff, resonance, modulation) => {
+                    synth.voices[layer].set_filter(mode, cutoff_to_hz(cutoff), resonance_to_amount(resonance), modulation);
+                },
+                OutputEvent::SendUpdate (layer, effect, send) => {
+                    synth.voices[layer].set_send(effect, send_to_gain(send));
+                },
+                OutputEvent::DelayUpdate (delay) => {
+                    synth.delay.set_division(delay.division);
+                    synth.delay.feedback = feedback_to_amount(delay.feedback);
+                    synth.delay.damping = damping_to_amount(delay.damping);
+                },
+                OutputEvent::ReverbUpdate (reverb) => {
+                    synth.reverb.decay = decay_to_amount(reverb.decay);
+                    synth.reverb.damping = damping_to_amount(reverb.damping);
+                },
+                OutputEvent::SidechainUpdate (sidechain) => {
+                    synth.sidechain.source = sidechain.source;
+                    synth.sidechain.depth = depth_to_amount(sidechain.depth);
+                    synth.sidechain.release_ms = sidechain.release_ms as f32;
+                },
+                OutputEvent::MasterUpdate (master) => {
+                    synth.master.compressor.bypass = master.compress == 0;
+                    synth.master.compressor.threshold = compress_to_threshold(master.compress);
+                    synth.master.bitcrusher.bypass = master.crush == 0 && master.downsample == 1;
+                    synth.master.bitcrusher.set_bits(crush_to_bits(master.crush));
+                    synth.master.bitcrusher.downsample = master.downsample as u32;
+                    synth.master.soft_clip.bypass = master.drive == 0;
+                    synth.master.soft_clip.drive = drive_to_gain(master.drive);
+                },
+                OutputEvent::RoutingUpdate => {
+                    synth.set_routing(ui.routing());
                 },
                 OutputEvent::TempoUpdate (tempo) => {
-                    // TODO
+                    set_step_period(tim4, step_period_us(tempo_to_bpm(tempo)));
+                    synth.delay.set_tempo(tempo_to_bpm(tempo));
                 },
                 OutputEvent::IsPlaying (is_playing) => {
                     tim4.cr1.modify(|_, w| w.cen().bit(is_playing)); 
                     sequencer.reset_steps();
                 },
+                OutputEvent::DumpMidi => {
+                    // A bar as MIDI file, sent below
+                    MIDI_DUMP.clear();
+                    let mut overflow = false;
+                    write_smf(sequencer, &ui.layers, tempo_to_bpm(ui.tempo), 1, SmfFormat::MultiTrack, |bytes| {
+                        overflow |= MIDI_DUMP.try_extend_from_slice(bytes).is_err();
+                    });
+                    if overflow {
+                        // Rather nothing than a truncated file
+                        MIDI_DUMP.clear();
+                        iprintln!(&mut itm.stim[0], "MIDI dump over {} bytes", MIDI_DUMP_LEN);
+                    }
+                    *MIDI_SENT = 0;
+                },
             }
 
         }
 
+        // Out of the audio callback, which can't wait for the ITM
+        if *cx.resources.max_cycles > *PRINTED_CYCLES {
+            *PRINTED_CYCLES = *cx.resources.max_cycles;
+            iprintln!(&mut itm.stim[0], "audio callback {} cycles", *PRINTED_CYCLES);
+        }
+
+        // Send the MIDI file on stimulus port 1 as far as the FIFO takes it, waiting
+        // for the ITM would hold up the audio task of the same priority
+        let stim = &mut itm.stim[1];
+        while *MIDI_SENT < MIDI_DUMP.len() && stim.is_fifo_ready() {
+            stim.write_u8(MIDI_DUMP[*MIDI_SENT]);
+            *MIDI_SENT += 1;
+        }
+
         let new_led_data = render(ui, sequencer);
         cx.resources.led_data.lock(|led_data| {
             let _ = mem::replace(led_data, new_led_data);
@@ -0,0 +1,191 @@
+use crate::sequencer::Sequencer;
+use crate::synthesizer::gm_note;
+use crate::ui::LayerState;
+
+const DRUM_CHANNEL: u8 = 9;
+const TICKS_PER_QUARTER: u16 = 96;
+const TICKS_PER_STEP: u32 = TICKS_PER_QUARTER as u32 / 4;
+const NOTE_TICKS: u32 = TICKS_PER_STEP / 2;
+
+// Enough bytes for a bar of three layers in either format, with every step hit
+pub const MAX_BAR_LEN: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SmfFormat {
+    // Type 0, all layers in one track
+    SingleTrack,
+    // Type 1, a tempo track followed by a track per layer
+    MultiTrack,
+}
+
+// Writes the sequences as a Standard MIDI File, looping each layer from its first
+// step for the given number of bars of 16 steps. The file is passed to `out` in
+// pieces, so it can be streamed without buffering.
+pub fn write_smf<F, const NUM_SEQS: usize, const MAX_SEQLEN: usize>(
+    sequencer: &Sequencer<NUM_SEQS, MAX_SEQLEN>,
+    layers: &[LayerState; NUM_SEQS],
+    bpm: u32,
+    bars: usize,
+    format: SmfFormat,
+    mut out: F,
+) where F: FnMut(&[u8]) {
+    let all_layers = (1 << NUM_SEQS) - 1;
+    let (format_type, num_tracks) = match format {
+        SmfFormat::SingleTrack => (0u16, 1u16),
+        SmfFormat::MultiTrack => (1, NUM_SEQS as u16 + 1),
+    };
+    out(b"MThd");
+    out(&6u32.to_be_bytes());
+    out(&format_type.to_be_bytes());
+    out(&num_tracks.to_be_bytes());
+    out(&TICKS_PER_QUARTER.to_be_bytes());
+
+    match format {
+        SmfFormat::SingleTrack => {
+            write_track(sequencer, layers, bpm, bars, true, all_layers, &mut out);
+        },
+        SmfFormat::MultiTrack => {
+            write_track(sequencer, layers, bpm, bars, true, 0, &mut out);
+            for i in 0..NUM_SEQS {
+                write_track(sequencer, layers, bpm, bars, false, 1 << i, &mut out);
+            }
+        },
+    }
+}
+
+// Writes a track chunk with the notes of the layers in the `notes` mask
+fn write_track<F, const NUM_SEQS: usize, const MAX_SEQLEN: usize>(
+    sequencer: &Sequencer<NUM_SEQS, MAX_SEQLEN>,
+    layers: &[LayerState; NUM_SEQS],
+    bpm: u32,
+    bars: usize,
+    tempo: bool,
+    notes: u32,
+    out: &mut F,
+) where F: FnMut(&[u8]) {
+    // The chunk starts with its length, so the events are generated twice
+    let mut len = 0;
+    write_events(sequencer, layers, bpm, bars, tempo, notes, &mut |bytes: &[u8]| len += bytes.len());
+    out(b"MTrk");
+    out(&(len as u32).to_be_bytes());
+    write_events(sequencer, layers, bpm, bars, tempo, notes, out);
+}
+
+fn write_events<F, const NUM_SEQS: usize, const MAX_SEQLEN: usize>(
+    sequencer: &Sequencer<NUM_SEQS, MAX_SEQLEN>,
+    layers: &[LayerState; NUM_SEQS],
+    bpm: u32,
+    bars: usize,
+    tempo: bool,
+    notes: u32,
+    out: &mut F,
+) where F: FnMut(&[u8]) {
+    // Tempo and 4/4 time signature
+    if tempo {
+        out(&[0, 0xFF, 0x51, 0x03]);
+        out(&(60_000_000 / bpm).to_be_bytes()[1..]);
+        out(&[0, 0xFF, 0x58, 0x04, 4, 2, 24, 8]);
+    }
+
+    let mut tick = 0;
+    let mut last_tick = 0;
+    let steps = bars * 16;
+    for step in 0..steps {
+        for &note_on in &[true, false] {
+            for (i, seq) in sequencer.sequences.iter().enumerate() {
+                let velocity = seq.get(step % seq.len().max(1)).cloned().unwrap_or(0);
+                if notes & 1 << i == 0 || velocity == 0 {
+                    continue;
+                }
+                write_var_len(tick - last_tick, out);
+                last_tick = tick;
+                let note = gm_note(layers[i].sound);
+                if note_on {
+                    out(&[0x90 | DRUM_CHANNEL, note, velocity]);
+                } else {
+                    out(&[0x80 | DRUM_CHANNEL, note, 0]);
+                }
+            }
+            tick += if note_on { NOTE_TICKS } else { TICKS_PER_STEP - NOTE_TICKS };
+        }
+    }
+
+    // End of track, after the last step
+    let end = steps as u32 * TICKS_PER_STEP;
+    write_var_len(end - last_tick, out);
+    out(&[0xFF, 0x2F, 0x00]);
+}
+
+fn write_var_len<F: FnMut(&[u8])>(value: u32, out: &mut F) {
+    let mut bytes = [0u8; 4];
+    let mut i = bytes.len() - 1;
+    let mut value = value;
+    bytes[i] = (value & 0x7F) as u8;
+    while value > 0x7F {
+        value >>= 7;
+        i -= 1;
+        bytes[i] = (value & 0x7F) as u8 | 0x80;
+    }
+    out(&bytes[i..]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ui::UiState;
+
+    fn to_vec<const N: usize>(sequencer: &Sequencer<N, 16>, layers: &[LayerState; N], format: SmfFormat) -> std::vec::Vec<u8> {
+        let mut bytes = vec![];
+        write_smf(sequencer, layers, 120, 1, format, |b| bytes.extend_from_slice(b));
+        bytes
+    }
+
+    #[test]
+    fn var_len() {
+        for &(value, expected) in &[(0, &[0x00][..]), (0x7F, &[0x7F]), (0x80, &[0x81, 0x00]), (0x3FFF, &[0xFF, 0x7F])] {
+            let mut bytes = vec![];
+            write_var_len(value, &mut |b: &[u8]| bytes.extend_from_slice(b));
+            assert_eq!(bytes, expected);
+        }
+    }
+
+    #[test]
+    fn single_track() {
+        let mut sequencer: Sequencer<1, 16> = Default::default();
+        sequencer.set_sequence(0, 16, 1, 0, 1);
+        let layers = UiState::<1>::default().layers;
+        let expected = [
+            b"MThd".to_vec(), vec![0, 0, 0, 6, 0, 0, 0, 1, 0, 96],
+            b"MTrk".to_vec(), vec![0, 0, 0, 28],
+            vec![0, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20],
+            vec![0, 0xFF, 0x58, 0x04, 4, 2, 24, 8],
+            vec![0, 0x99, 36, 127],
+            vec![12, 0x89, 36, 0],
+            vec![0x82, 0x74, 0xFF, 0x2F, 0x00],
+        ].concat();
+        assert_eq!(to_vec(&sequencer, &layers, SmfFormat::SingleTrack), expected);
+    }
+
+    #[test]
+    fn multi_track() {
+        let sequencer: Sequencer<3, 16> = Default::default();
+        let layers = UiState::<3>::default().layers;
+        let bytes = to_vec(&sequencer, &layers, SmfFormat::MultiTrack);
+        assert_eq!(&bytes[8..12], &[0, 1, 0, 4]);
+        assert_eq!(bytes.windows(4).filter(|w| w == b"MTrk").count(), 4);
+    }
+
+    #[test]
+    fn full_bar_fits() {
+        let layers = UiState::<3>::default().layers;
+        for len in 1..=16 {
+            let mut sequencer: Sequencer<3, 16> = Default::default();
+            for i in 0..3 {
+                sequencer.set_sequence(i, len, len, 0, len);
+            }
+            for &format in &[SmfFormat::SingleTrack, SmfFormat::MultiTrack] {
+                assert!(to_vec(&sequencer, &layers, format).len() <= MAX_BAR_LEN);
+            }
+        }
+    }
+}
@@ -2,9 +2,19 @@ use euclidean_rhythm::euclidean_rhythm;
 use arrayvec::ArrayVec;
 use array_init::array_init;
 use itertools::izip;
+use core::cmp;
+
+pub const VELOCITY_NORMAL: u8 = 96;
+pub const VELOCITY_ACCENT: u8 = 127;
+
+// Steps are sixteenth notes
+pub fn step_period_us(bpm: u32) -> u32 {
+    15_000_000 / bpm
+}
 
 #[derive(Debug)]
 pub struct Sequencer<const NUM_SEQS: usize, const MAX_SEQLEN: usize> {
+    // Velocity per step, 0 is a rest
     pub sequences: [ArrayVec<u8, MAX_SEQLEN>; NUM_SEQS],
     pub steps: [usize; NUM_SEQS],
 }
@@ -20,9 +30,15 @@ impl<const NUM_SEQS: usize, const MAX_SEQLEN: usize> Default for Sequencer<NUM_S
 }
 
 impl<const NUM_SEQS: usize, const MAX_SEQLEN: usize> Sequencer<NUM_SEQS, MAX_SEQLEN> {
-    pub fn set_sequence(&mut self, i: usize, len: usize, hits: usize, shift: isize) {
+    pub fn set_sequence(&mut self, i: usize, len: usize, hits: usize, shift: isize, accents: usize) {
         let shift = shift.rem_euclid(len as isize) as usize;
         self.sequences[i] = euclidean_rhythm(hits, len);
+
+        // Accents are spread as an Euclidean rhythm over the hits
+        let accent_pattern: ArrayVec<u8, MAX_SEQLEN> = euclidean_rhythm(cmp::min(accents, hits), hits);
+        for (v, &accent) in self.sequences[i].iter_mut().filter(|v| **v == 1).zip(&accent_pattern) {
+            *v = if accent == 1 { VELOCITY_ACCENT } else { VELOCITY_NORMAL };
+        }
         self.sequences[i].rotate_right(shift);
         self.steps[i] = 0;
     }
@@ -31,17 +47,47 @@ impl<const NUM_SEQS: usize, const MAX_SEQLEN: usize> Sequencer<NUM_SEQS, MAX_SEQ
         self.steps = [0; NUM_SEQS];
     }
 
-    pub fn step(&mut self) -> [bool; NUM_SEQS] {
-        let mut gates = [false; NUM_SEQS];
+    pub fn step(&mut self) -> [u8; NUM_SEQS] {
+        let mut velocities = [0; NUM_SEQS];
 
-        for (step, seq, gate) in izip!(self.steps.iter_mut(), &self.sequences, gates.iter_mut()) {
-            *gate = seq[*step] == 1;
+        for (step, seq, velocity) in izip!(self.steps.iter_mut(), &self.sequences, velocities.iter_mut()) {
+            *velocity = seq[*step];
 
             *step += 1;
             if *step >= seq.len() {
                 *step = 0;
             }
         }
-        gates
+        velocities
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accents_spread_over_hits() {
+        let mut sequencer: Sequencer<1, 16> = Default::default();
+        sequencer.set_sequence(0, 16, 4, 0, 2);
+        let (a, n) = (VELOCITY_ACCENT, VELOCITY_NORMAL);
+        assert_eq!(&sequencer.sequences[0][..], &[a, 0, 0, 0, n, 0, 0, 0, a, 0, 0, 0, n, 0, 0, 0]);
+
+        // More accents than hits accents them all, and the shift moves the accents with the hits
+        sequencer.set_sequence(0, 5, 2, 1, 3);
+        assert_eq!(&sequencer.sequences[0][..], &[0, a, 0, a, 0]);
+    }
+
+    #[test]
+    fn step_emits_velocities() {
+        let mut sequencer: Sequencer<2, 16> = Default::default();
+        sequencer.set_sequence(0, 4, 2, 0, 1);
+        sequencer.set_sequence(1, 3, 1, 1, 0);
+        let velocities: std::vec::Vec<_> = (0..6).map(|_| sequencer.step()).collect();
+        let (a, n) = (VELOCITY_ACCENT, VELOCITY_NORMAL);
+        assert_eq!(velocities, [[a, 0], [0, n], [n, 0], [0, 0], [a, n], [0, 0]]);
+
+        // Back to the first step of each layer
+        sequencer.reset_steps();
+        assert_eq!(sequencer.step(), [a, 0]);
+    }
+}
@@ -1,101 +1,933 @@
-use stm32f7::stm32f7x2::{DMA1};
+use core::cmp;
+use crate::hal::AudioSink;
+
+pub mod delay;
+pub mod drums;
+pub mod filter;
+pub mod master;
+pub mod reverb;
+pub mod sidechain;
+
+use delay::Delay;
+use reverb::Reverb;
+use sidechain::Sidechain;
+use drums::{Drum, DrumVoice, NUM_DRUMS};
+use filter::{Filter, FilterMod, FilterMode};
+use master::MasterBus;
 
 pub const SAMPLE_FREQ: u32 = 44_100;
 
 pub const BUFFER_LEN: usize = 1024;  // Half buffer filled at around 100Hz
 
-static KICK: &[u8; 13230] = include_bytes!("../sounds/kick.pcm");
-static SNARE: &[u8; 19200] = include_bytes!("../sounds/snare.pcm");
-static HIHAT: &[u8; 4410] = include_bytes!("../sounds/hihat.pcm");
+// Samples the voices render at once, a half buffer
+pub const BLOCK_LEN: usize = BUFFER_LEN / 2;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum DmaState { Ht, Tc, Error, Unknown }
 
 
-static SOUND_STORE: [&[u8]; 3] = [KICK, SNARE, HIHAT];
+#[derive(Copy, Clone)]
+pub enum SampleData {
+    // Unsigned with 128 as zero
+    U8(&'static [u8]),
+    I16(&'static [i16]),
+}
 
-pub struct SynthVoice {
-    pub sound: usize,
-    pub volume: f32,
+impl SampleData {
+    pub fn len(&self) -> usize {
+        match self {
+            SampleData::U8(data) => data.len(),
+            SampleData::I16(data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Sample between -1 and 1
+    pub fn get(&self, i: usize) -> f32 {
+        match self {
+            SampleData::U8(data) => data[i] as f32 / 128. - 1.,
+            SampleData::I16(data) => data[i] as f32 / 32768.,
+        }
+    }
+
+    // Sample in Q15
+    pub fn get_q15(&self, i: usize) -> i32 {
+        match self {
+            SampleData::U8(data) => (data[i] as i32 - 128) << 8,
+            SampleData::I16(data) => data[i] as i32,
+        }
+    }
+
+    // Samples i - 1 to i + 2 of the part from start to end in playback direction, silent outside of it
+    fn neighbours_q15(&self, (start, end): (usize, usize), i: usize, reverse: bool) -> [i32; 4] {
+        let len = (end - start) as isize;
+        let get = |k: isize| {
+            if k < 0 || k >= len {
+                0
+            } else {
+                self.get_q15(start + if reverse { len - 1 - k } else { k } as usize)
+            }
+        };
+        let i = i as isize;
+        [get(i - 1), get(i), get(i + 1), get(i + 2)]
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    // Catmull-Rom spline, less aliasing when pitched up at twice the reads
+    Hermite,
+}
+
+// Value between the second and third point at frac, with 16 fractional bits
+fn interpolate([xm1, x0, x1, x2]: [f32; 4], frac: u32, interpolation: Interpolation) -> f32 {
+    let t = frac as f32 / (1 << 16) as f32;
+    match interpolation {
+        Interpolation::Linear => x0 + (x1 - x0) * t,
+        Interpolation::Hermite => {
+            let c1 = 0.5 * (x1 - xm1);
+            let c2 = xm1 - 2.5 * x0 + 2. * x1 - 0.5 * x2;
+            let c3 = 0.5 * (x2 - xm1) + 1.5 * (x0 - x1);
+            ((c3 * t + c2) * t + c1) * t + x0
+        },
+    }
+}
+
+fn interpolate_q15(points: [i32; 4], frac: u32, interpolation: Interpolation) -> i32 {
+    let [xm1, x0, x1, x2] = points.map(i64::from);
+    let t = frac as i64;
+    match interpolation {
+        Interpolation::Linear => (x0 + (((x1 - x0) * t) >> 16)) as i32,
+        // Twice the coefficients to stay in integers
+        Interpolation::Hermite => {
+            let c1 = x1 - xm1;
+            let c2 = 2 * xm1 - 5 * x0 + 4 * x1 - x2;
+            let c3 = x2 - xm1 + 3 * (x0 - x1);
+            let y = (((((c3 * t) >> 16) + c2) * t) >> 16) + c1;
+            ((((y * t) >> 16) + 2 * x0) >> 1) as i32
+        },
+    }
+}
+
+pub struct Sound {
+    pub name: &'static str,
+    // General MIDI percussion note, for the MIDI export
+    pub gm_note: u8,
+    pub data: SampleData,
+}
+
+// Generated by build.rs from the sources in sounds/
+include!(concat!(env!("OUT_DIR"), "/sounds.rs"));
+
+// The sounds must be resampled to the rate they are played at
+const _: () = assert!(SOUND_STORE_FREQ == SAMPLE_FREQ);
+
+// The samples followed by the synthesized drums
+pub const NUM_SOUNDS: usize = NUM_SAMPLES + NUM_DRUMS;
+
+pub fn sound_name(sound: usize) -> &'static str {
+    if sound < NUM_SAMPLES {
+        SOUND_STORE[sound].name
+    } else {
+        Drum::name(sound - NUM_SAMPLES)
+    }
+}
+
+pub fn gm_note(sound: usize) -> u8 {
+    if sound < NUM_SAMPLES {
+        SOUND_STORE[sound].gm_note
+    } else {
+        Drum::gm_note(sound - NUM_SAMPLES)
+    }
+}
+
+// Settings of a voice, which each kind of voice interprets or ignores
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Param {
+    // Semitones
+    Tune,
+    // Milliseconds
+    Attack,
+    Decay,
+    // Part of the sound that plays, as fractions of its length
+    Start,
+    End,
+    // 1 plays backwards
+    Reverse,
+    // Hits that overlap
+    Polyphony,
+    // Main frequency in Hz and a second control of the sound, specific to the voice
+    Tone,
+    Color,
+}
+
+pub const NUM_PARAMS: usize = 9;
+
+impl Param {
+    pub const ALL: [Param; NUM_PARAMS] = [
+        Param::Tune, Param::Attack, Param::Decay, Param::Start, Param::End,
+        Param::Reverse, Param::Polyphony, Param::Tone, Param::Color,
+    ];
+}
+
+// A source of hits, the output includes the velocity but not the volume and pan of the layer
+pub trait Voice {
+    fn trigger(&mut self, velocity: u8);
+    // Fades out the hits that play
+    fn choke(&mut self);
+    fn is_active(&self) -> bool;
+    // Adds the next samples, about between -1 and 1
+    fn render(&mut self, block: &mut [f32]);
+    // Same in Q15
+    fn render_q15(&mut self, block: &mut [i32]);
+    // None when unset or not a parameter of the voice
+    fn param(&self, param: Param) -> Option<f32>;
+    // None restores the default
+    fn set_param(&mut self, param: Param, value: Option<f32>);
+}
+
+// DAC outputs of a voice when the outputs are independent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Output {
+    Out1,
+    Out2,
+    Both,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Routing<const NUM_VOICES: usize> {
+    // Mix by the pan of the voices, DAC_OUT1 left and DAC_OUT2 right
+    Stereo,
+    Independent([Output; NUM_VOICES]),
+}
+
+// Effects the voices send to, in parallel to the mix
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Effect {
+    Delay,
+    Reverb,
+}
+
+pub const NUM_EFFECTS: usize = 2;
+
+// What a new hit does with the hits of a layer that are still playing
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Retrigger {
+    // Fades out the previous hit
+    Choke,
+    // Overlaps up to the given number of hits, fading out the oldest
+    Poly(usize),
+    // Chokes the previous hit and the hits of the layers in the same group
+    Group(u8),
+}
+
+pub const MAX_POLYPHONY: usize = 4;
+// One more than the polyphony, so the oldest hit can fade out while the new one starts
+const NUM_PLAYHEADS: usize = MAX_POLYPHONY + 1;
+
+// Length of the fade out of a choked hit, about 3 ms
+const CHOKE_FADE_SHIFT: u32 = 7;
+const CHOKE_FADE_LEN: u32 = 1 << CHOKE_FADE_SHIFT;
+
+// Playback rate of 1 with 16 fractional bits
+const UNITY_RATE: u32 = 1 << 16;
+
+// Envelope level of 1 with 24 fractional bits
+const ENVELOPE_FULL: u32 = 1 << 24;
+
+// Attack and decay of the hits, linear in amplitude
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Envelope {
+    // Samples
+    attack_len: u32,
+    attack_step: u32,
+    // None plays the sound to its end
+    decay_step: Option<u32>,
+}
+
+impl Envelope {
+    pub const NONE: Envelope = Envelope { attack_len: 0, attack_step: ENVELOPE_FULL, decay_step: None };
+
+    pub fn from_ms(attack_ms: u32, decay_ms: Option<u32>) -> Self {
+        let to_len = |ms: u32| cmp::max(ms * SAMPLE_FREQ / 1000, 1);
+        Envelope {
+            attack_len: attack_ms * SAMPLE_FREQ / 1000,
+            attack_step: ENVELOPE_FULL / to_len(attack_ms),
+            // Rounded up to end in time
+            decay_step: decay_ms.map(|ms| ENVELOPE_FULL.div_ceil(to_len(ms))),
+        }
+    }
+
+    fn initial_level(&self) -> u32 {
+        if self.attack_len == 0 { ENVELOPE_FULL } else { 0 }
+    }
+}
+
+// A playing hit of a voice
+#[derive(Copy, Clone)]
+struct Playhead {
+    // Position in the sound in playback direction, with 16 fractional bits
     step: usize,
+    frac: u32,
+    velocity: f32,
+    velocity_q14: i32,
+    // Remaining samples when fading out
+    fade: Option<u32>,
+    // Samples since the hit and the envelope level
+    age: u32,
+    level: u32,
     playing: bool,
 }
 
+impl Playhead {
+    const IDLE: Playhead = Playhead {
+        step: 0,
+        frac: 0,
+        velocity: 0.,
+        velocity_q14: 0,
+        fade: None,
+        age: 0,
+        level: 0,
+        playing: false,
+    };
+
+    fn choke(&mut self) {
+        if self.playing && self.fade.is_none() {
+            self.fade = Some(CHOKE_FADE_LEN);
+        }
+    }
+
+    // Position of the next sample and its amplitude in Q14 by the envelope and fade, if playing
+    fn advance(&mut self, len: usize, rate: u32, envelope: &Envelope) -> Option<(usize, u32, i32)> {
+        if !self.playing {
+            return None;
+        }
+        if self.step >= len {
+            self.playing = false;
+            return None;
+        }
+        let (step, frac) = (self.step, self.frac);
+        self.frac += rate;
+        self.step += (self.frac >> 16) as usize;
+        self.frac &= UNITY_RATE - 1;
+        self.playing = self.step < len;
+
+        let level = self.level;
+        self.age = self.age.saturating_add(1);
+        if self.age < envelope.attack_len {
+            self.level = cmp::min(self.level + envelope.attack_step, ENVELOPE_FULL);
+        } else if self.age == envelope.attack_len {
+            self.level = ENVELOPE_FULL;
+        } else if let Some(decay_step) = envelope.decay_step {
+            self.level = self.level.saturating_sub(decay_step);
+            self.playing &= self.level > 0;
+        }
+
+        let fade = match self.fade.as_mut() {
+            Some(fade) => {
+                *fade -= 1;
+                self.playing &= *fade > 0;
+                *fade + 1
+            },
+            None => CHOKE_FADE_LEN,
+        };
+        Some((step, frac, (((level >> 10) * fade) >> CHOKE_FADE_SHIFT) as i32))
+    }
+}
+
+// Plays hits of a sample
+pub struct Sampler {
+    sound: usize,
+    pub interpolation: Interpolation,
+    reverse: bool,
+    polyphony: usize,
+    attack_ms: u32,
+    decay_ms: Option<u32>,
+    envelope: Envelope,
+    // Semitones and the playback rate with 16 fractional bits
+    tune: f32,
+    rate: u32,
+    // Part of the sound that plays, as fraction of its length with 16 fractional bits
+    start: u32,
+    end: u32,
+    playheads: [Playhead; NUM_PLAYHEADS],
+}
+
+impl Sampler {
+    pub fn new(sound: usize) -> Self {
+        Sampler {
+            sound,
+            interpolation: Interpolation::Linear,
+            reverse: false,
+            polyphony: 1,
+            attack_ms: 0,
+            decay_ms: None,
+            envelope: Envelope::NONE,
+            tune: 0.,
+            rate: UNITY_RATE,
+            start: 0,
+            end: 1 << 16,
+            playheads: [Playhead::IDLE; NUM_PLAYHEADS],
+        }
+    }
+
+    // First and last sample of the trimmed sound
+    fn region(&self) -> (usize, usize) {
+        let len = SOUND_STORE[self.sound].data.len() as u64;
+        let start = ((len * self.start as u64) >> 16) as usize;
+        let end = ((len * self.end as u64) >> 16) as usize;
+        (start, cmp::max(start, end))
+    }
+
+}
+
+impl Voice for Sampler {
+    fn trigger(&mut self, velocity: u8) {
+        // Fade out the oldest hits beyond the polyphony
+        let is_sounding = |playhead: &&mut Playhead| playhead.playing && playhead.fade.is_none();
+        while self.playheads.iter_mut().filter(is_sounding).count() >= self.polyphony {
+            if let Some(oldest) = self.playheads.iter_mut().filter(is_sounding).max_by_key(|playhead| playhead.step) {
+                oldest.choke();
+            }
+        }
+
+        // An idle playhead, or else cut the one closest to its end
+        let playhead = self.playheads.iter_mut()
+            .min_by_key(|playhead| match (playhead.playing, playhead.fade) {
+                (false, _) => (0, 0),
+                (true, Some(fade)) => (1, fade as usize),
+                (true, None) => (2, usize::MAX - playhead.step),
+            })
+            .unwrap();
+        let velocity = velocity as f32 / 127.;
+        *playhead = Playhead {
+            velocity,
+            velocity_q14: (velocity * (1 << 14) as f32 + 0.5) as i32,
+            level: self.envelope.initial_level(),
+            playing: true,
+            ..Playhead::IDLE
+        };
+    }
+
+    fn choke(&mut self) {
+        for playhead in self.playheads.iter_mut() {
+            playhead.choke();
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.playheads.iter().any(|playhead| playhead.playing)
+    }
+
+    // The sound and its region are looked up once per block, then each hit renders until it ends
+    fn render(&mut self, block: &mut [f32]) {
+        let sound = SOUND_STORE[self.sound].data;
+        let region = self.region();
+        for playhead in self.playheads.iter_mut().filter(|playhead| playhead.playing) {
+            for v in block.iter_mut() {
+                match playhead.advance(region.1 - region.0, self.rate, &self.envelope) {
+                    Some((i, frac, amp)) => {
+                        let points = sound.neighbours_q15(region, i, self.reverse).map(|x| x as f32 / 32768.);
+                        *v += interpolate(points, frac, self.interpolation) * playhead.velocity * amp as f32 / (1 << 14) as f32;
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn render_q15(&mut self, block: &mut [i32]) {
+        let sound = SOUND_STORE[self.sound].data;
+        let region = self.region();
+        for playhead in self.playheads.iter_mut().filter(|playhead| playhead.playing) {
+            for v in block.iter_mut() {
+                match playhead.advance(region.1 - region.0, self.rate, &self.envelope) {
+                    Some((i, frac, amp)) => {
+                        let val = interpolate_q15(sound.neighbours_q15(region, i, self.reverse), frac, self.interpolation);
+                        *v += (((val * playhead.velocity_q14) >> 14) * amp) >> 14;
+                    },
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn param(&self, param: Param) -> Option<f32> {
+        match param {
+            Param::Tune => Some(self.tune),
+            Param::Attack => Some(self.attack_ms as f32),
+            Param::Decay => self.decay_ms.map(|ms| ms as f32),
+            Param::Start => Some(self.start as f32 / (1 << 16) as f32),
+            Param::End => Some(self.end as f32 / (1 << 16) as f32),
+            Param::Reverse => Some(self.reverse as u8 as f32),
+            Param::Polyphony => Some(self.polyphony as f32),
+            Param::Tone | Param::Color => None,
+        }
+    }
+
+    fn set_param(&mut self, param: Param, value: Option<f32>) {
+        // Beyond the sound, the region would read past the end of the sample data
+        let fraction = |value: Option<f32>, default: f32| (value.unwrap_or(default).clamp(0., 1.) * (1 << 16) as f32) as u32;
+        match param {
+            Param::Tune => {
+                self.tune = value.unwrap_or(0.);
+                self.rate = (libm::exp2f(self.tune / 12.) * UNITY_RATE as f32 + 0.5) as u32;
+            },
+            Param::Attack | Param::Decay => {
+                if param == Param::Attack {
+                    self.attack_ms = value.map_or(0, |ms| ms as u32);
+                } else {
+                    self.decay_ms = value.map(|ms| ms as u32);
+                }
+                self.envelope = Envelope::from_ms(self.attack_ms, self.decay_ms);
+            },
+            Param::Start => self.start = fraction(value, 0.),
+            Param::End => self.end = fraction(value, 1.),
+            Param::Reverse => self.reverse = value.is_some_and(|reverse| reverse != 0.),
+            Param::Polyphony => self.polyphony = value.map_or(1, |hits| (hits as usize).clamp(1, MAX_POLYPHONY)),
+            Param::Tone | Param::Color => (),
+        }
+    }
+}
+
+// The kinds of voices, dispatched by a match without boxing. A new kind of voice adds a variant
+// and its arm in each method.
+pub enum Engine {
+    Sampler(Sampler),
+    Drum(DrumVoice),
+}
+
+impl Engine {
+    pub fn new(sound: usize) -> Self {
+        if sound < NUM_SAMPLES {
+            Engine::Sampler(Sampler::new(sound))
+        } else {
+            Engine::Drum(DrumVoice::new(sound - NUM_SAMPLES))
+        }
+    }
+}
+
+impl Voice for Engine {
+    fn trigger(&mut self, velocity: u8) {
+        match self {
+            Engine::Sampler(sampler) => sampler.trigger(velocity),
+            Engine::Drum(drum) => drum.trigger(velocity),
+        }
+    }
+
+    fn choke(&mut self) {
+        match self {
+            Engine::Sampler(sampler) => sampler.choke(),
+            Engine::Drum(drum) => drum.choke(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        match self {
+            Engine::Sampler(sampler) => sampler.is_active(),
+            Engine::Drum(drum) => drum.is_active(),
+        }
+    }
+
+    fn render(&mut self, block: &mut [f32]) {
+        match self {
+            Engine::Sampler(sampler) => sampler.render(block),
+            Engine::Drum(drum) => drum.render(block),
+        }
+    }
+
+    fn render_q15(&mut self, block: &mut [i32]) {
+        match self {
+            Engine::Sampler(sampler) => sampler.render_q15(block),
+            Engine::Drum(drum) => drum.render_q15(block),
+        }
+    }
+
+    fn param(&self, param: Param) -> Option<f32> {
+        match self {
+            Engine::Sampler(sampler) => sampler.param(param),
+            Engine::Drum(drum) => drum.param(param),
+        }
+    }
+
+    fn set_param(&mut self, param: Param, value: Option<f32>) {
+        match self {
+            Engine::Sampler(sampler) => sampler.set_param(param, value),
+            Engine::Drum(drum) => drum.set_param(param, value),
+        }
+    }
+}
+
+// A layer of the instrument, which keeps its settings when its sound changes
+pub struct SynthVoice {
+    sound: usize,
+    engine: Engine,
+    params: [Option<f32>; NUM_PARAMS],
+    interpolation: Interpolation,
+    retrigger: Retrigger,
+    filter: Filter,
+    volume: f32,
+    // From -1 for left to 1 for right
+    pan: f32,
+    // Replaces the pan when routed to independent outputs
+    output: Option<Output>,
+    // volume * pan law or output per channel, at most 2
+    gains: [f32; 2],
+    gains_q14: [i32; 2],
+    // Levels into the effects, after the gains
+    sends: [f32; NUM_EFFECTS],
+    send_gains: [[f32; 2]; NUM_EFFECTS],
+    send_gains_q14: [[i32; 2]; NUM_EFFECTS],
+}
+
 impl SynthVoice {
     pub fn new(sound: usize) -> Self {
-        SynthVoice {
+        let mut voice = SynthVoice {
             sound,
+            engine: Engine::new(sound),
+            params: [None; NUM_PARAMS],
+            interpolation: Interpolation::Linear,
+            retrigger: Retrigger::Choke,
+            filter: Filter::OFF,
             volume: 1.,
-            step: 0,
-            playing: false,
+            pan: 0.,
+            output: None,
+            gains: [0.; 2],
+            gains_q14: [0; 2],
+            sends: [0.; NUM_EFFECTS],
+            send_gains: [[0.; 2]; NUM_EFFECTS],
+            send_gains_q14: [[0; 2]; NUM_EFFECTS],
+        };
+        voice.update_gains();
+        voice
+    }
+
+    pub fn sound(&self) -> usize {
+        self.sound
+    }
+
+    pub fn set_sound(&mut self, sound: usize) {
+        self.sound = sound;
+        self.engine = Engine::new(sound);
+        for (&param, &value) in Param::ALL.iter().zip(self.params.iter()) {
+            self.engine.set_param(param, value);
         }
+        self.set_interpolation(self.interpolation);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume;
+        self.update_gains();
     }
 
-    fn apply_gate(&mut self, gate: bool) {
-        if gate {
-            self.step = 0;
-            self.playing = true;
+    pub fn pan(&self) -> f32 {
+        self.pan
+    }
+
+    pub fn set_pan(&mut self, pan: f32) {
+        self.pan = pan;
+        self.update_gains();
+    }
+
+    pub fn send(&self, effect: Effect) -> f32 {
+        self.sends[effect as usize]
+    }
+
+    pub fn set_send(&mut self, effect: Effect, send: f32) {
+        self.sends[effect as usize] = send;
+        self.update_gains();
+    }
+
+    pub fn retrigger(&self) -> Retrigger {
+        self.retrigger
+    }
+
+    pub fn set_retrigger(&mut self, retrigger: Retrigger) {
+        self.retrigger = retrigger;
+        let polyphony = match retrigger {
+            Retrigger::Choke | Retrigger::Group(_) => 1,
+            Retrigger::Poly(hits) => hits,
+        };
+        self.set_param(Param::Polyphony, Some(polyphony as f32));
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: Interpolation) {
+        self.interpolation = interpolation;
+        if let Engine::Sampler(sampler) = &mut self.engine {
+            sampler.interpolation = interpolation;
         }
     }
 
-    fn step(&mut self) -> Option<f32> {
-        let sound = SOUND_STORE[self.sound];
-        if self.playing {
-            if self.step < sound.len() {
-                let val = (sound[self.step] as f32) / 128. - 1.;
-                self.step += 1;
-                Some(val * self.volume)
-            } else {
-                self.playing = false;
-                None
+    // Pitch relative to the sound in semitones
+    pub fn set_tune(&mut self, semitones: f32) {
+        self.set_param(Param::Tune, Some(semitones));
+    }
+
+    pub fn set_reverse(&mut self, reverse: bool) {
+        self.set_param(Param::Reverse, Some(reverse as u8 as f32));
+    }
+
+    // The drums have their own decay, which the decay replaces when set
+    pub fn set_envelope(&mut self, attack_ms: u32, decay_ms: Option<u32>) {
+        self.set_param(Param::Attack, Some(attack_ms as f32));
+        self.set_param(Param::Decay, decay_ms.map(|ms| ms as f32));
+    }
+
+    // Start and end as fractions of the sound
+    pub fn set_trim(&mut self, start: f32, end: f32) {
+        self.set_param(Param::Start, Some(start));
+        self.set_param(Param::End, Some(end));
+    }
+
+    // Cutoff in Hz and resonance from 0 to 1
+    pub fn set_filter(&mut self, mode: FilterMode, cutoff: f32, resonance: f32, modulation: FilterMod) {
+        self.filter.mode = mode;
+        self.filter.cutoff = cutoff;
+        self.filter.resonance = resonance;
+        self.filter.modulation = modulation;
+    }
+
+    fn update_gains(&mut self) {
+        let channel_gains = match self.output {
+            // Constant power pan law, so the loudness stays the same across the stereo field
+            None => {
+                let angle = (self.pan + 1.) * core::f32::consts::FRAC_PI_4;
+                [libm::cosf(angle), libm::sinf(angle)]
+            },
+            Some(Output::Out1) => [1., 0.],
+            Some(Output::Out2) => [0., 1.],
+            Some(Output::Both) => [1., 1.],
+        };
+        for ((gain, gain_q14), channel_gain) in self.gains.iter_mut().zip(self.gains_q14.iter_mut()).zip(channel_gains.iter()) {
+            *gain = self.volume * channel_gain;
+            *gain_q14 = (*gain * (1 << 14) as f32 + 0.5) as i32;
+        }
+        for ((send_gains, send_gains_q14), &send) in self.send_gains.iter_mut().zip(self.send_gains_q14.iter_mut()).zip(self.sends.iter()) {
+            *send_gains = self.gains.map(|gain| gain * send);
+            *send_gains_q14 = send_gains.map(|gain| (gain * (1 << 14) as f32 + 0.5) as i32);
+        }
+    }
+}
+
+impl Voice for SynthVoice {
+    fn trigger(&mut self, velocity: u8) {
+        if velocity > 0 {
+            // What is left in the filter of a silent voice is below the silence level
+            if !self.is_active() {
+                self.filter.reset();
             }
-        } else {
-            None
+            self.engine.trigger(velocity);
+            self.filter.trigger(velocity);
         }
     }
+
+    fn choke(&mut self) {
+        self.engine.choke();
+    }
+
+    // Includes the tail of a resonant filter after the hits end
+    fn is_active(&self) -> bool {
+        self.engine.is_active() || self.filter.is_ringing()
+    }
+
+    fn render(&mut self, block: &mut [f32]) {
+        self.engine.render(block);
+        self.filter.process(block);
+    }
+
+    fn render_q15(&mut self, block: &mut [i32]) {
+        self.engine.render_q15(block);
+        self.filter.process_q15(block);
+    }
+
+    fn param(&self, param: Param) -> Option<f32> {
+        self.engine.param(param)
+    }
+
+    fn set_param(&mut self, param: Param, value: Option<f32>) {
+        self.params[param as usize] = value;
+        self.engine.set_param(param, value);
+    }
 }
 
 pub struct Synth<const NUM_VOICES: usize> {
     pub voices: [SynthVoice; NUM_VOICES],
+    pub delay: Delay,
+    pub reverb: Reverb,
+    pub sidechain: Sidechain,
+    pub master: MasterBus,
+    routing: Routing<NUM_VOICES>,
 }
 
 impl<const NUM_VOICES: usize> Synth<NUM_VOICES> {
-    pub fn apply_gates(&mut self, gates: [bool; NUM_VOICES]) {
-        for (voice, &gate) in self.voices.iter_mut().zip(gates.iter()) {
-            voice.apply_gate(gate);
+    pub fn new(voices: [SynthVoice; NUM_VOICES]) -> Self {
+        Synth { voices, delay: Delay::default(), reverb: Reverb::default(), sidechain: Sidechain::default(), master: MasterBus::default(), routing: Routing::Stereo }
+    }
+
+    pub fn routing(&self) -> Routing<NUM_VOICES> {
+        self.routing
+    }
+
+    pub fn set_routing(&mut self, routing: Routing<NUM_VOICES>) {
+        self.routing = routing;
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            voice.output = match routing {
+                Routing::Stereo => None,
+                Routing::Independent(outputs) => Some(outputs[i]),
+            };
+            voice.update_gains();
+        }
+    }
+
+    pub fn apply_gates(&mut self, velocities: [u8; NUM_VOICES]) {
+        // Hits choke the other layers of their group
+        for (i, &velocity) in velocities.iter().enumerate() {
+            if let (Retrigger::Group(group), true) = (self.voices[i].retrigger(), velocity > 0) {
+                for (j, voice) in self.voices.iter_mut().enumerate() {
+                    if j != i && voice.retrigger() == Retrigger::Group(group) {
+                        voice.choke();
+                    }
+                }
+            }
+        }
+        for (voice, &velocity) in self.voices.iter_mut().zip(velocities.iter()) {
+            voice.trigger(velocity);
+        }
+        // Ducks from the next frame on, the same frame the hit starts
+        if let Some(&velocity) = self.sidechain.source.and_then(|source| velocities.get(source)) {
+            if velocity > 0 {
+                self.sidechain.trigger(velocity);
+            }
         }
     }
 
-    fn step(&mut self) -> f32 {
-        let mut v = 0.;
-        for voice in self.voices.iter_mut() {
-            v += voice.step().unwrap_or(0.);
+    // Mixes the voices into left and right frames, a block at a time
+    pub fn render(&mut self, frames: &mut [[f32; 2]]) {
+        let mut block = [0.; BLOCK_LEN];
+        let mut sends = [[[0.; 2]; BLOCK_LEN]; NUM_EFFECTS];
+        let mut ducking = [0.; BLOCK_LEN];
+        for frames in frames.chunks_mut(BLOCK_LEN) {
+            frames.fill([0.; 2]);
+            for sends in sends.iter_mut() {
+                sends[..frames.len()].fill([0.; 2]);
+            }
+            let source = self.sidechain.source;
+            let ducking = &mut ducking[..frames.len()];
+            if source.is_some() {
+                self.sidechain.process(ducking);
+            }
+            for (i, voice) in self.voices.iter_mut().enumerate().filter(|(_, voice)| voice.is_active()) {
+                let block = &mut block[..frames.len()];
+                block.fill(0.);
+                voice.render(block);
+                if source.is_some() && source != Some(i) {
+                    for (v, &gain) in block.iter_mut().zip(ducking.iter()) {
+                        *v *= gain;
+                    }
+                }
+                for (frame, &v) in frames.iter_mut().zip(block.iter()) {
+                    frame[0] += v * voice.gains[0];
+                    frame[1] += v * voice.gains[1];
+                }
+                for ((sends, &level), gains) in sends.iter_mut().zip(voice.sends.iter()).zip(voice.send_gains.iter()) {
+                    if level > 0. {
+                        for (send, &v) in sends.iter_mut().zip(block.iter()) {
+                            send[0] += v * gains[0];
+                            send[1] += v * gains[1];
+                        }
+                    }
+                }
+            }
+            let [delay_sends, reverb_sends] = &sends;
+            self.delay.process(&delay_sends[..frames.len()], frames);
+            self.reverb.process(&reverb_sends[..frames.len()], frames);
+            self.master.process(frames);
+            // Only clips when the soft clip is bypassed
+            for v in frames.iter_mut().flatten() {
+                *v = v.clamp(-1., 1.);
+            }
         }
-        if v > 1. {
-            v = 1.;
-        } else if v < -1. {
-            v = -1.;
+    }
+
+    // Same as render, in Q15 with a wider accumulator so the sum can't overflow
+    pub fn render_q15(&mut self, frames: &mut [[i32; 2]]) {
+        let mut block = [0; BLOCK_LEN];
+        let mut sends = [[[0; 2]; BLOCK_LEN]; NUM_EFFECTS];
+        let mut ducking = [0.; BLOCK_LEN];
+        let mut ducking_q15 = [0; BLOCK_LEN];
+        for frames in frames.chunks_mut(BLOCK_LEN) {
+            frames.fill([0; 2]);
+            for sends in sends.iter_mut() {
+                sends[..frames.len()].fill([0; 2]);
+            }
+            // The ducking is computed in float, only when there is a source
+            let source = self.sidechain.source;
+            let ducking_q15 = &mut ducking_q15[..frames.len()];
+            if source.is_some() {
+                let ducking = &mut ducking[..frames.len()];
+                self.sidechain.process(ducking);
+                for (gain_q15, &gain) in ducking_q15.iter_mut().zip(ducking.iter()) {
+                    *gain_q15 = (gain * (1 << 15) as f32) as i32;
+                }
+            }
+            for (i, voice) in self.voices.iter_mut().enumerate().filter(|(_, voice)| voice.is_active()) {
+                let block = &mut block[..frames.len()];
+                block.fill(0);
+                voice.render_q15(block);
+                if source.is_some() && source != Some(i) {
+                    for (v, &gain) in block.iter_mut().zip(ducking_q15.iter()) {
+                        *v = ((*v as i64 * gain as i64) >> 15) as i32;
+                    }
+                }
+                for (frame, &v) in frames.iter_mut().zip(block.iter()) {
+                    frame[0] += ((v as i64 * voice.gains_q14[0] as i64) >> 14) as i32;
+                    frame[1] += ((v as i64 * voice.gains_q14[1] as i64) >> 14) as i32;
+                }
+                for ((sends, &level), gains) in sends.iter_mut().zip(voice.sends.iter()).zip(voice.send_gains_q14.iter()) {
+                    if level > 0. {
+                        for (send, &v) in sends.iter_mut().zip(block.iter()) {
+                            send[0] += ((v as i64 * gains[0] as i64) >> 14) as i32;
+                            send[1] += ((v as i64 * gains[1] as i64) >> 14) as i32;
+                        }
+                    }
+                }
+            }
+            let [delay_sends, reverb_sends] = &sends;
+            self.delay.process_q15(&delay_sends[..frames.len()], frames);
+            self.reverb.process_q15(&reverb_sends[..frames.len()], frames);
+            self.master.process_q15(frames);
+            for v in frames.iter_mut().flatten() {
+                *v = (*v).clamp(-1 << 15, (1 << 15) - 1);
+            }
         }
-        v
+    }
+
+    // Left and right
+    pub fn step(&mut self) -> [f32; 2] {
+        let mut frame = [[0.; 2]];
+        self.render(&mut frame);
+        frame[0]
+    }
+
+    pub fn step_q15(&mut self) -> [i32; 2] {
+        let mut frame = [[0; 2]];
+        self.render_q15(&mut frame);
+        frame[0]
     }
 }
 
-pub fn dma_handler<const NUM_VOICES: usize>(dma1: &DMA1, buffer: &mut [u32; BUFFER_LEN], synth: &mut Synth<NUM_VOICES>) -> DmaState {
+pub fn dma_handler<S: AudioSink, const NUM_VOICES: usize>(sink: &mut S, buffer: &mut [u32; BUFFER_LEN], synth: &mut Synth<NUM_VOICES>) -> DmaState {
     let mid = buffer.len() / 2;
 
-    let isr = dma1.hisr.read();
-    let state = if isr.tcif5().is_complete() {
-        dma1.hifcr.write(|w| w.ctcif5().clear());
-        DmaState::Tc
-    } else if isr.htif5().is_half() {
-        dma1.hifcr.write(|w| w.chtif5().clear());
-        DmaState::Ht
-    } else if isr.teif5().is_error() {
-        dma1.hifcr.write(|w| w.cteif5().clear());
-        DmaState::Error
-    } else {
-        DmaState::Unknown
-    };
+    let state = sink.poll();
 
     match state {
         DmaState::Ht => synth_callback(&mut buffer[0..mid], synth),
@@ -106,12 +938,460 @@ pub fn dma_handler<const NUM_VOICES: usize>(dma1: &DMA1, buffer: &mut [u32; BUFF
 }
 
 fn synth_callback<const NUM_VOICES: usize>(buffer: &mut [u32], synth: &mut Synth<NUM_VOICES>) {
-    for val in buffer.iter_mut() {
-        let v = synth.step();
-        let v_12bit = ((v + 1.) * 2047.5) as u32;
+    if cfg!(feature = "fixed-point") {
+        fill_fixed(buffer, synth);
+    } else {
+        fill_float(buffer, synth);
+    }
+}
+
+// DAC_OUT1 is the left channel, DAC_OUT2 the right
+fn dac_word([left, right]: [u32; 2]) -> u32 {
+    let channel_1 = left;
+    let channel_2 = right;
+    (channel_2 << 16) + channel_1
+}
+
+pub fn fill_float<const NUM_VOICES: usize>(buffer: &mut [u32], synth: &mut Synth<NUM_VOICES>) {
+    let mut frames = [[0.; 2]; BLOCK_LEN];
+    for buffer in buffer.chunks_mut(BLOCK_LEN) {
+        let frames = &mut frames[..buffer.len()];
+        synth.render(frames);
+        for (val, frame) in buffer.iter_mut().zip(frames.iter()) {
+            *val = dac_word(frame.map(|v| ((v + 1.) * 2047.5) as u32));
+        }
+    }
+}
+
+// Within one 12 bit step of fill_float, without float arithmetic per sample
+pub fn fill_fixed<const NUM_VOICES: usize>(buffer: &mut [u32], synth: &mut Synth<NUM_VOICES>) {
+    let mut frames = [[0; 2]; BLOCK_LEN];
+    for buffer in buffer.chunks_mut(BLOCK_LEN) {
+        let frames = &mut frames[..buffer.len()];
+        synth.render_q15(frames);
+        for (val, frame) in buffer.iter_mut().zip(frames.iter()) {
+            *val = dac_word(frame.map(|v| (((v + (1 << 15)) * 4095) >> 16) as u32));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::mock::MockAudioSink;
+
+    #[test]
+    fn dma_handler_fills_buffer_halves() {
+        let mut synth = Synth::new([SynthVoice::new(0)]);
+        let mut sink = MockAudioSink::default();
+        let mut buffer = [0; BUFFER_LEN];
+        let silence = (2047 << 16) + 2047;
+
+        assert_eq!(dma_handler(&mut sink, &mut buffer, &mut synth), DmaState::Ht);
+        assert!(buffer[..BUFFER_LEN / 2].iter().all(|&v| v == silence));
+        assert!(buffer[BUFFER_LEN / 2..].iter().all(|&v| v == 0));
+
+        synth.apply_gates([127]);
+        assert_eq!(dma_handler(&mut sink, &mut buffer, &mut synth), DmaState::Tc);
+        assert!(buffer[BUFFER_LEN / 2..].iter().any(|&v| v != silence));
+    }
+
+    #[test]
+    fn fixed_point_matches_float() {
+        let new_synth = || {
+            let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(1), SynthVoice::new(2)]);
+            for (voice, &(volume, pan)) in synth.voices.iter_mut().zip([(2., -1.), (0.75, 0.), (1.25, 0.4)].iter()) {
+                voice.set_volume(volume);
+                voice.set_pan(pan);
+            }
+            synth.voices[1].set_tune(-7.);
+            synth.voices[1].set_interpolation(Interpolation::Hermite);
+            synth.voices[2].set_tune(3.);
+            synth.voices[2].set_reverse(true);
+            synth.voices[2].set_envelope(5, Some(60));
+            synth.voices[1].set_trim(0.25, 0.75);
+            synth
+        };
+        // Bypassed, the mix clips at full scale. The soft clip saturates above the knee at half
+        // of full scale, 1023 and 3071 on the DAC, and stays below full scale.
+        for &bypass in [true, false].iter() {
+            let mut float_synth = new_synth();
+            let mut fixed_synth = new_synth();
+            float_synth.master.soft_clip.bypass = bypass;
+            fixed_synth.master.soft_clip.bypass = bypass;
+            let mut float_buffer = [0; BUFFER_LEN];
+            let mut fixed_buffer = [0; BUFFER_LEN];
+            let (mut min, mut max) = (4095, 0);
+
+            // Loud enough to clip, with a retrigger at a lower velocity while playing
+            for (i, velocities) in [[127, 127, 127], [0, 0, 0], [0, 40, 100], [90, 0, 0]].iter().cycle().take(32).enumerate() {
+                float_synth.apply_gates(*velocities);
+                fixed_synth.apply_gates(*velocities);
+                fill_float(&mut float_buffer, &mut float_synth);
+                fill_fixed(&mut fixed_buffer, &mut fixed_synth);
+                for (&float, &fixed) in float_buffer.iter().zip(fixed_buffer.iter()) {
+                    for shift in [0, 16].iter() {
+                        let (float, fixed) = ((float >> shift & 0xFFF) as i32, (fixed >> shift & 0xFFF) as i32);
+                        assert!((float - fixed).abs() <= 1, "buffer {}: float {} fixed {}", i, float, fixed);
+                        min = min.min(float);
+                        max = max.max(float);
+                    }
+                }
+            }
+            if bypass {
+                assert_eq!(max, 4095);
+            } else {
+                assert!(max > 3071 && max < 4095, "{}", max);
+                assert!(min > 0, "{}", min);
+            }
+        }
+    }
+
+    #[test]
+    fn pan_keeps_power() {
+        let mut voice = SynthVoice::new(0);
+        for &pan in [-1., -0.5, 0., 0.3, 1.].iter() {
+            voice.set_pan(pan);
+            let [left, right] = voice.gains;
+            assert!((left * left + right * right - 1.).abs() < 1e-6);
+        }
+        voice.set_pan(-1.);
+        assert_eq!(voice.gains_q14, [1 << 14, 0]);
+    }
+
+    #[test]
+    fn independent_outputs_ignore_pan() {
+        let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(1), SynthVoice::new(2)]);
+        synth.voices[0].set_pan(1.);
+        synth.set_routing(Routing::Independent([Output::Out1, Output::Out2, Output::Both]));
+        let gains: Vec<_> = synth.voices.iter().map(|voice| voice.gains).collect();
+        assert_eq!(gains, [[1., 0.], [0., 1.], [1., 1.]]);
+        let gains: Vec<_> = synth.voices.iter().map(|voice| voice.gains_q14).collect();
+        assert_eq!(gains, [[1 << 14, 0], [0, 1 << 14], [1 << 14, 1 << 14]]);
+
+        synth.set_routing(Routing::Stereo);
+        let [left, right] = synth.voices[0].gains;
+        assert!(left.abs() < 1e-6);
+        assert!((right - 1.).abs() < 1e-6);
+        assert_eq!(synth.voices[0].gains_q14, [0, 1 << 14]);
+    }
+
+    fn sampler(voice: &SynthVoice) -> &Sampler {
+        match &voice.engine {
+            Engine::Sampler(sampler) => sampler,
+            Engine::Drum(_) => panic!("not a sample"),
+        }
+    }
+
+    fn num_sounding(voice: &SynthVoice) -> usize {
+        sampler(voice).playheads.iter().filter(|playhead| playhead.playing && playhead.fade.is_none()).count()
+    }
+
+    // Renders until the fades of choked hits are done
+    fn render_fade<const N: usize>(synth: &mut Synth<N>) {
+        for _ in 0..CHOKE_FADE_LEN {
+            synth.step();
+        }
+    }
+
+    #[test]
+    fn retrigger_modes() {
+        let mut synth = Synth::new([SynthVoice::new(1), SynthVoice::new(1), SynthVoice::new(2), SynthVoice::new(2)]);
+        synth.voices[1].set_retrigger(Retrigger::Poly(3));
+        synth.voices[2].set_retrigger(Retrigger::Group(0));
+        synth.voices[3].set_retrigger(Retrigger::Group(0));
+        for _ in 0..4 {
+            synth.apply_gates([127, 127, 0, 127]);
+            render_fade(&mut synth);
+        }
+        assert_eq!(num_sounding(&synth.voices[0]), 1);
+        assert_eq!(sampler(&synth.voices[0]).playheads.iter().filter(|playhead| playhead.playing).count(), 1);
+        assert_eq!(num_sounding(&synth.voices[1]), 3);
+
+        // The open hat cuts the closed one
+        synth.apply_gates([0, 0, 127, 0]);
+        render_fade(&mut synth);
+        assert!(synth.voices[2].is_active());
+        assert!(!synth.voices[3].is_active());
+    }
+
+    #[test]
+    fn choke_fades_out() {
+        let mut synth = Synth::new([SynthVoice::new(0)]);
+        synth.apply_gates([127]);
+        for _ in 0..1000 {
+            synth.step();
+        }
+        synth.apply_gates([127]);
+        // The new hit starts at the first sample, the choked one continues
+        let fading = sampler(&synth.voices[0]).playheads.iter().find(|playhead| playhead.fade.is_some()).unwrap().step;
+        let [left, _] = synth.step();
+        let sound = SOUND_STORE[0].data;
+        let expected = (sound.get(0) + sound.get(fading)) * synth.voices[0].gains[0];
+        assert!((left - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn full_polyphony_fades_oldest() {
+        let mut synth = Synth::new([SynthVoice::new(0)]);
+        synth.voices[0].set_retrigger(Retrigger::Poly(MAX_POLYPHONY));
+        for _ in 0..MAX_POLYPHONY {
+            synth.apply_gates([127]);
+            for _ in 0..100 {
+                synth.step();
+            }
+        }
+        synth.apply_gates([127]);
+        assert_eq!(num_sounding(&synth.voices[0]), MAX_POLYPHONY);
+        let oldest = sampler(&synth.voices[0]).playheads.iter().position(|playhead| playhead.fade.is_some()).unwrap();
+        for _ in 0..CHOKE_FADE_LEN - 1 {
+            synth.step();
+            assert!(sampler(&synth.voices[0]).playheads[oldest].playing);
+        }
+        synth.step();
+        assert!(!sampler(&synth.voices[0]).playheads[oldest].playing);
+        assert_eq!(num_sounding(&synth.voices[0]), MAX_POLYPHONY);
+    }
+
+    #[test]
+    fn filter_tail_plays_out() {
+        let mut synth = Synth::new([SynthVoice::new(0)]);
+        synth.voices[0].set_envelope(0, Some(20));
+        synth.voices[0].set_filter(FilterMode::Lowpass, 200., 1., FilterMod::None);
+        synth.apply_gates([127]);
+        while synth.voices[0].engine.is_active() {
+            synth.step();
+        }
+        let mut tail = 0;
+        let mut peak = 0f32;
+        while synth.voices[0].is_active() {
+            peak = peak.max(synth.step()[0].abs());
+            tail += 1;
+            assert!(tail < SAMPLE_FREQ, "the filter rings forever");
+        }
+        assert!(tail > 1000);
+        assert!(peak > 0.01);
+    }
+
+    // Renders a single hit of the first sound, until silent
+    fn render_hit(voice: SynthVoice) -> Vec<f32> {
+        let mut synth = Synth::new([voice]);
+        synth.voices[0].set_pan(-1.);
+        synth.apply_gates([127]);
+        let mut audio = Vec::new();
+        while synth.voices[0].is_active() {
+            audio.push(synth.step()[0]);
+        }
+        audio
+    }
+
+    // Straightforward resampling of the sound as reference
+    fn resample_linear(rate: f64, reverse: bool) -> Vec<f32> {
+        let sound = SOUND_STORE[0].data;
+        let mut samples: Vec<f64> = (0..sound.len()).map(|i| sound.get(i) as f64).collect();
+        if reverse {
+            samples.reverse();
+        }
+        samples.push(0.);
+        (0..)
+            .map(|i| i as f64 * rate)
+            .take_while(|&pos| pos < sound.len() as f64)
+            .map(|pos| {
+                let (i, t) = (pos as usize, pos.fract());
+                (samples[i] * (1. - t) + samples[i + 1] * t) as f32
+            })
+            .collect()
+    }
+
+    fn assert_close(audio: &[f32], reference: &[f32]) {
+        assert_eq!(audio.len(), reference.len());
+        for (i, (&v, &r)) in audio.iter().zip(reference.iter()).enumerate() {
+            assert!((v - r).abs() < 1e-4, "sample {}: {} != {}", i, v, r);
+        }
+    }
+
+    #[test]
+    fn tune_resamples() {
+        for &(semitones, reverse) in [(0., false), (-12., false), (7., false), (-5., true)].iter() {
+            let mut voice = SynthVoice::new(0);
+            voice.set_tune(semitones);
+            voice.set_reverse(reverse);
+            let rate = sampler(&voice).rate as f64 / UNITY_RATE as f64;
+            assert!((rate - 2f64.powf(semitones as f64 / 12.)).abs() < 1e-4);
+            assert_close(&render_hit(voice), &resample_linear(rate, reverse));
+        }
+    }
+
+    #[test]
+    fn hermite_interpolates_through_samples() {
+        let mut voice = SynthVoice::new(0);
+        voice.set_interpolation(Interpolation::Hermite);
+        assert_close(&render_hit(voice), &resample_linear(1., false));
+
+        // A parabola is reproduced exactly, linear interpolation cuts its corner
+        let points = [1., 0., 1., 4.];
+        assert_eq!(interpolate(points, 1 << 15, Interpolation::Hermite), 0.25);
+        assert_eq!(interpolate(points, 1 << 15, Interpolation::Linear), 0.5);
+        let points_q15 = [1 << 12, 0, 1 << 12, 4 << 12];
+        assert_eq!(interpolate_q15(points_q15, 1 << 15, Interpolation::Hermite), 1 << 10);
+    }
+
+    #[test]
+    fn trim_plays_part() {
+        let mut voice = SynthVoice::new(0);
+        voice.set_trim(0.25, 0.5);
+        let sound = SOUND_STORE[0].data;
+        let reference: Vec<f32> = (sound.len() / 4..sound.len() / 2).map(|i| sound.get(i)).collect();
+        assert_close(&render_hit(voice), &reference);
+    }
+
+    #[test]
+    fn trim_clamps_to_sound() {
+        let mut voice = SynthVoice::new(0);
+        voice.set_trim(-0.5, 1.5);
+        assert_eq!(voice.param(Param::Start), Some(0.));
+        assert_eq!(voice.param(Param::End), Some(1.));
+        assert_eq!(render_hit(voice).len(), SOUND_STORE[0].data.len());
+    }
+
+    #[test]
+    fn envelope_shapes_hit() {
+        let mut voice = SynthVoice::new(0);
+        voice.set_envelope(1, Some(10));
+        let audio = render_hit(voice);
+        let reference = resample_linear(1., false);
+        // 1 ms of attack and 10 ms of decay
+        assert_eq!(audio.len(), 44 + 441);
+        for (i, (&v, &r)) in audio.iter().zip(reference.iter()).enumerate() {
+            let level = if i < 44 { i as f32 / 44. } else { 1. - (i - 44) as f32 / 441. };
+            assert!((v - r * level).abs() < 1e-3, "sample {}: {} != {}", i, v, r * level);
+        }
+    }
+
+    #[test]
+    fn sample_formats_share_scale() {
+        let u8_data = SampleData::U8(&[0, 64, 128, 192]);
+        let i16_data = SampleData::I16(&[-32768, -16384, 0, 16384]);
+        for i in 0..4 {
+            assert_eq!(u8_data.get(i), i16_data.get(i));
+            assert_eq!(u8_data.get_q15(i), i16_data.get_q15(i));
+        }
+    }
 
-        let channel_1 = v_12bit;
-        let channel_2 = v_12bit;
-        *val = (channel_2 << 16) + channel_1;
+    #[test]
+    fn sound_store_matches_sources() {
+        assert_eq!(SOUND_STORE.iter().map(|sound| sound.name).collect::<Vec<_>>(), ["kick", "snare", "hihat"]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sounds_have_percussion_notes() {
+        assert_eq!(gm_note(0), 36);
+        assert_eq!(gm_note(NUM_SAMPLES), 35);
+        assert!((0..NUM_SOUNDS).all(|sound| (35..=81).contains(&gm_note(sound))));
+    }
+
+    #[test]
+    fn drum_sounds_play() {
+        let mut synth = Synth::new([SynthVoice::new(NUM_SAMPLES)]);
+        assert_eq!(sound_name(synth.voices[0].sound()), "synth kick");
+        synth.voices[0].set_pan(-1.);
+        synth.apply_gates([127]);
+        let audio: Vec<_> = (0..4410).map(|_| synth.step()).collect();
+        assert!(audio.iter().any(|[left, _]| left.abs() > 0.1));
+        assert!(audio.iter().all(|[_, right]| right.abs() < 1e-6));
+
+        // Back to the sample, the drum hit stops and the settings of the layer stay
+        synth.voices[0].set_tune(-3.);
+        synth.voices[0].set_retrigger(Retrigger::Poly(2));
+        synth.voices[0].set_sound(0);
+        assert!(!synth.voices[0].is_active());
+        assert_eq!(synth.voices[0].param(Param::Tune), Some(-3.));
+        assert_eq!(synth.voices[0].param(Param::Polyphony), Some(2.));
+        assert_eq!(synth.voices[0].param(Param::Tone), None);
+    }
+
+    #[test]
+    fn render_matches_step() {
+        // Overlapping hits of a sample and a drum, across a block boundary
+        let new_synth = || {
+            let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(NUM_SAMPLES + 1)]);
+            synth.voices[0].set_retrigger(Retrigger::Poly(3));
+            synth.apply_gates([127, 100]);
+            for _ in 0..300 {
+                synth.step();
+            }
+            synth.apply_gates([90, 0]);
+            synth
+        };
+        let (mut stepped, mut rendered) = (new_synth(), new_synth());
+        let mut frames = [[0.; 2]; BLOCK_LEN + 100];
+        rendered.render(&mut frames);
+        for frame in frames.iter() {
+            assert_eq!(stepped.step(), *frame);
+        }
+
+        let (mut stepped, mut rendered) = (new_synth(), new_synth());
+        let mut frames = [[0; 2]; BLOCK_LEN + 100];
+        rendered.render_q15(&mut frames);
+        for frame in frames.iter() {
+            assert_eq!(stepped.step_q15(), *frame);
+        }
+    }
+
+    #[test]
+    fn sidechain_ducks_from_hit() {
+        // A silent source, so the output is only the ducked layer
+        let new_synth = |source| {
+            let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(NUM_SAMPLES)]);
+            synth.voices[0].set_volume(0.);
+            synth.sidechain.source = source;
+            synth.apply_gates([0, 127]);
+            synth
+        };
+        let (mut ducked, mut dry) = (new_synth(Some(0)), new_synth(None));
+        let mut frames = [[0.; 2]; 300];
+        let mut dry_frames = [[0.; 2]; 300];
+        ducked.render(&mut frames[..100]);
+        dry.render(&mut dry_frames[..100]);
+        assert_eq!(frames[..100], dry_frames[..100]);
+
+        ducked.apply_gates([127, 0]);
+        dry.apply_gates([127, 0]);
+        ducked.render(&mut frames[100..]);
+        dry.render(&mut dry_frames[100..]);
+        let gain = |i: usize| frames[i][0] / dry_frames[i][0];
+        assert!(gain(100) < 1. && gain(100) > 0.99);
+        assert!((gain(100 + SAMPLE_FREQ as usize / 500) - 0.5).abs() < 0.01);
+
+        let mut ducked_q15 = new_synth(Some(0));
+        let mut frames_q15 = [[0; 2]; 300];
+        ducked_q15.render_q15(&mut frames_q15[..100]);
+        ducked_q15.apply_gates([127, 0]);
+        ducked_q15.render_q15(&mut frames_q15[100..]);
+        for (frame, frame_q15) in frames.iter().zip(frames_q15.iter()) {
+            assert!((frame[0] * 32768. - frame_q15[0] as f32).abs() < 4.);
+        }
+    }
+
+    #[test]
+    fn delay_echoes_sends() {
+        let render_hit = |send: f32| {
+            let mut synth = Synth::new([SynthVoice::new(0)]);
+            synth.delay = Delay::new(vec![[0; 2]; delay::DELAY_LEN].leak());
+            synth.delay.set_division(delay::Division::Sixteenth);
+            synth.delay.feedback = 0.;
+            synth.voices[0].set_send(Effect::Delay, send);
+            synth.apply_gates([127]);
+            let mut frames = vec![[0.; 2]; 2 * synth.delay.time()];
+            synth.render(&mut frames);
+            frames
+        };
+        let dry = render_hit(0.);
+        let wet = render_hit(0.5);
+        let time = dry.len() / 2;
+        for i in 0..dry.len() {
+            let echo = if i < time { 0. } else { 0.5 * dry[i - time][0] };
+            assert!((wet[i][0] - dry[i][0] - echo).abs() < 1e-4, "{}", i);
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use super::SAMPLE_FREQ;
+
+// Frames of the delay line, 743 ms in 128 KB of the 256 KB SRAM
+pub const DELAY_LEN: usize = 1 << 15;
+
+pub type DelayLine = [[i16; 2]; DELAY_LEN];
+
+// The feedback path turns the highs down by up to this much per repeat
+const MAX_DAMPING: f32 = 0.9;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Division {
+    Sixteenth,
+    EighthTriplet,
+    Eighth,
+    DottedEighth,
+    Quarter,
+    DottedQuarter,
+}
+
+impl Division {
+    // From short to long
+    const ALL: [Division; 6] = [
+        Division::Sixteenth,
+        Division::EighthTriplet,
+        Division::Eighth,
+        Division::DottedEighth,
+        Division::Quarter,
+        Division::DottedQuarter,
+    ];
+
+    // Length in thirds of a sixteenth, so the triplet is whole
+    fn thirds(self) -> u32 {
+        match self {
+            Division::Sixteenth => 3,
+            Division::EighthTriplet => 4,
+            Division::Eighth => 6,
+            Division::DottedEighth => 9,
+            Division::Quarter => 12,
+            Division::DottedQuarter => 18,
+        }
+    }
+}
+
+// Stereo echo of the sends, with a lowpass in the feedback so the repeats get darker
+pub struct Delay {
+    // From 0 to below 1
+    pub feedback: f32,
+    // From 0 to 1
+    pub damping: f32,
+    division: Division,
+    bpm: u32,
+    line: &'static mut [[i16; 2]],
+    // Write position and delay in frames
+    pos: usize,
+    len: usize,
+    lowpass: [f32; 2],
+    lowpass_q15: [i32; 2],
+}
+
+impl Default for Delay {
+    // Without a line, which leaves the sends silent
+    fn default() -> Self {
+        Delay::new(&mut [])
+    }
+}
+
+impl Delay {
+    pub fn new(line: &'static mut [[i16; 2]]) -> Self {
+        let mut delay = Delay {
+            feedback: 0.4,
+            damping: 0.4,
+            division: Division::Eighth,
+            bpm: 120,
+            line,
+            pos: 0,
+            len: 1,
+            lowpass: [0.; 2],
+            lowpass_q15: [0; 2],
+        };
+        delay.update_len();
+        delay
+    }
+
+    pub fn division(&self) -> Division {
+        self.division
+    }
+
+    pub fn set_division(&mut self, division: Division) {
+        self.division = division;
+        self.update_len();
+    }
+
+    // Follows the tempo of the sequencer
+    pub fn set_tempo(&mut self, bpm: u32) {
+        self.bpm = bpm;
+        self.update_len();
+    }
+
+    // Delay in frames
+    pub fn time(&self) -> usize {
+        self.len
+    }
+
+    // The longest division up to the set one that fits the line, so slow tempos still echo in time
+    fn update_len(&mut self) {
+        let frames = |division: Division| (15 * SAMPLE_FREQ as u64 * division.thirds() as u64 / (3 * self.bpm as u64)) as usize;
+        let len = Division::ALL.iter()
+            .rev()
+            .filter(|division| division.thirds() <= self.division.thirds())
+            .map(|&division| frames(division))
+            .find(|&len| len <= self.line.len())
+            .unwrap_or(self.line.len());
+        self.len = len.max(1);
+    }
+
+    fn read_pos(&self) -> usize {
+        (self.pos + self.line.len() - self.len) % self.line.len()
+    }
+
+    // Adds the echoes to the frames and feeds the sends into the line
+    pub fn process(&mut self, sends: &[[f32; 2]], frames: &mut [[f32; 2]]) {
+        if self.line.is_empty() {
+            return;
+        }
+        let damping = self.damping * MAX_DAMPING;
+        let mut read = self.read_pos();
+        for (frame, send) in frames.iter_mut().zip(sends.iter()) {
+            let delayed = self.line[read].map(|v| v as f32 / 32768.);
+            for c in 0..2 {
+                self.lowpass[c] = delayed[c] + (self.lowpass[c] - delayed[c]) * damping;
+                // Saturates at full scale
+                self.line[self.pos][c] = ((send[c] + self.lowpass[c] * self.feedback) * 32768.) as i16;
+                frame[c] += delayed[c];
+            }
+            self.pos = if self.pos + 1 == self.line.len() { 0 } else { self.pos + 1 };
+            read = if read + 1 == self.line.len() { 0 } else { read + 1 };
+        }
+    }
+
+    pub fn process_q15(&mut self, sends: &[[i32; 2]], frames: &mut [[i32; 2]]) {
+        if self.line.is_empty() {
+            return;
+        }
+        let damping = (self.damping * MAX_DAMPING * (1 << 15) as f32) as i32;
+        let feedback = (self.feedback * (1 << 15) as f32) as i32;
+        let mut read = self.read_pos();
+        for (frame, send) in frames.iter_mut().zip(sends.iter()) {
+            let delayed = self.line[read].map(|v| v as i32);
+            for c in 0..2 {
+                self.lowpass_q15[c] = delayed[c] + (((self.lowpass_q15[c] - delayed[c]) * damping) >> 15);
+                let v = send[c] + ((self.lowpass_q15[c] * feedback) >> 15);
+                self.line[self.pos][c] = v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+                frame[c] += delayed[c];
+            }
+            self.pos = if self.pos + 1 == self.line.len() { 0 } else { self.pos + 1 };
+            read = if read + 1 == self.line.len() { 0 } else { read + 1 };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line() -> &'static mut [[i16; 2]] {
+        vec![[0; 2]; DELAY_LEN].leak()
+    }
+
+    // Frames from the first echo on at the given delay, of an impulse in the left channel
+    fn echoes(delay: &mut Delay, num_frames: usize) -> Vec<[f32; 2]> {
+        let mut sends = vec![[0.; 2]; num_frames];
+        sends[0] = [0.5, 0.];
+        let mut frames = vec![[0.; 2]; num_frames];
+        for (sends, frames) in sends.chunks(512).zip(frames.chunks_mut(512)) {
+            delay.process(sends, frames);
+        }
+        frames
+    }
+
+    #[test]
+    fn syncs_to_tempo() {
+        let mut delay = Delay::new(line());
+        // An eighth at 120 BPM is a quarter second
+        assert_eq!(delay.time(), 11025);
+        delay.set_division(Division::DottedEighth);
+        delay.set_tempo(150);
+        assert_eq!(delay.time(), 13230);
+        delay.set_division(Division::EighthTriplet);
+        assert_eq!(delay.time(), 5880);
+        // A dotted quarter and a dotted eighth at 50 BPM are too long for the line, an eighth fits
+        delay.set_division(Division::DottedQuarter);
+        delay.set_tempo(50);
+        assert_eq!(delay.time(), 26460);
+        assert_eq!(delay.division(), Division::DottedQuarter);
+        assert!(Delay::default().line.is_empty());
+    }
+
+    #[test]
+    fn echoes_decay_by_feedback() {
+        let mut delay = Delay::new(line());
+        delay.set_division(Division::Sixteenth);
+        delay.damping = 0.;
+        let len = delay.time();
+        let frames = echoes(&mut delay, 4 * len);
+        for (i, frame) in frames.iter().enumerate() {
+            let expected = if i > 0 && i % len == 0 { 0.5 * libm::powf(0.4, (i / len - 1) as f32) } else { 0. };
+            assert!((frame[0] - expected).abs() < 1e-3, "{}: {:?}", i, frame);
+            assert_eq!(frame[1], 0.);
+        }
+    }
+
+    #[test]
+    fn damping_smooths_repeats() {
+        let mut delay = Delay::new(line());
+        delay.set_division(Division::Sixteenth);
+        delay.damping = 1.;
+        let len = delay.time();
+        let frames = echoes(&mut delay, 3 * len);
+        // The impulse spreads out over the repeat, keeping its sum
+        assert!(frames[2 * len][0] < 0.5 * 0.4 * 0.2);
+        let sum: f32 = frames[2 * len..3 * len].iter().map(|frame| frame[0]).sum();
+        assert!((sum - 0.5 * 0.4).abs() < 1e-2, "{}", sum);
+    }
+
+    #[test]
+    fn fixed_point_matches_float() {
+        let mut delay = Delay::new(line());
+        delay.set_division(Division::Sixteenth);
+        let len = delay.time();
+        let frames = echoes(&mut delay, 3 * len);
+
+        let mut delay_q15 = Delay::new(line());
+        delay_q15.set_division(Division::Sixteenth);
+        let mut sends = vec![[0; 2]; 3 * len];
+        sends[0] = [1 << 14, 0];
+        let mut frames_q15 = vec![[0; 2]; 3 * len];
+        delay_q15.process_q15(&sends, &mut frames_q15);
+        for (frame, frame_q15) in frames.iter().zip(frames_q15.iter()) {
+            assert!((frame[0] * 32768. - frame_q15[0] as f32).abs() <= 4.);
+        }
+    }
+}
@@ -0,0 +1,685 @@
+use core::f32::consts::PI;
+use oorandom::Rand32;
+use super::{Param, Voice, CHOKE_FADE_LEN, SAMPLE_FREQ};
+
+// Synthesized drums, in the order they follow the samples
+pub const NUM_DRUMS: usize = 4;
+
+const DRUM_NAMES: [&str; NUM_DRUMS] = ["synth kick", "synth snare", "synth hat", "synth clap"];
+// General MIDI acoustic bass drum, electric snare, pedal hi-hat and hand clap
+const DRUM_GM_NOTES: [u8; NUM_DRUMS] = [35, 40, 44, 39];
+
+// Fixed seed so renders are reproducible
+const NOISE_SEED: u64 = 0x5eed;
+
+// Level below which a drum counts as silent, about -80 dB
+const SILENCE: f32 = 1e-4;
+
+// Multiplier per sample that decays by 60 dB in the given time
+fn decay_coef(ms: f32) -> f32 {
+    libm::expf(-6.9078 / (ms * SAMPLE_FREQ as f32 / 1000.))
+}
+
+fn semitones_to_ratio(semitones: f32) -> f32 {
+    libm::exp2f(semitones / 12.)
+}
+
+// Exponentially decaying amplitude, which fades out quickly when choked
+#[derive(Copy, Clone)]
+struct Decay {
+    level: f32,
+    coef: f32,
+    fade: Option<u32>,
+}
+
+impl Decay {
+    const SILENT: Decay = Decay { level: 0., coef: 0., fade: None };
+
+    fn trigger(&mut self, level: f32, ms: f32) {
+        *self = Decay { level, coef: decay_coef(ms), fade: None };
+    }
+
+    fn choke(&mut self) {
+        if self.is_active() && self.fade.is_none() {
+            self.fade = Some(CHOKE_FADE_LEN);
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        self.level > SILENCE
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.is_active() {
+            return 0.;
+        }
+        let mut out = self.level;
+        self.level *= self.coef;
+        if let Some(fade) = self.fade.as_mut() {
+            out *= *fade as f32 / CHOKE_FADE_LEN as f32;
+            *fade -= 1;
+            if *fade == 0 {
+                self.level = 0.;
+            }
+        }
+        out
+    }
+}
+
+// Second order filter with the coefficients of the audio EQ cookbook
+#[derive(Copy, Clone)]
+struct Biquad {
+    b: [f32; 3],
+    a: [f32; 2],
+    state: [f32; 2],
+}
+
+impl Biquad {
+    fn highpass(freq: f32, q: f32) -> Biquad {
+        let (cos, alpha) = Biquad::prewarp(freq, q);
+        Biquad::normalised([(1. + cos) / 2., -(1. + cos), (1. + cos) / 2.], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    fn bandpass(freq: f32, q: f32) -> Biquad {
+        let (cos, alpha) = Biquad::prewarp(freq, q);
+        Biquad::normalised([alpha, 0., -alpha], [1. + alpha, -2. * cos, 1. - alpha])
+    }
+
+    fn prewarp(freq: f32, q: f32) -> (f32, f32) {
+        // Keep the cutoff below Nyquist when tuned up
+        let w = 2. * PI * freq.min(0.45 * SAMPLE_FREQ as f32) / SAMPLE_FREQ as f32;
+        (libm::cosf(w), libm::sinf(w) / (2. * q))
+    }
+
+    fn normalised(b: [f32; 3], a: [f32; 3]) -> Biquad {
+        Biquad {
+            b: b.map(|x| x / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            state: [0.; 2],
+        }
+    }
+
+    // Transposed direct form II
+    fn step(&mut self, x: f32) -> f32 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// Phase as a fraction of a period, which moves less than a period per sample
+fn wrap_phase(phase: f32) -> f32 {
+    if phase >= 1. { phase - 1. } else { phase }
+}
+
+fn noise(rng: &mut Rand32) -> f32 {
+    rng.rand_float() * 2. - 1.
+}
+
+fn velocity_level(velocity: u8) -> f32 {
+    velocity as f32 / 127.
+}
+
+// Sine with a pitch that sweeps down from sweep times the pitch
+#[derive(Copy, Clone)]
+pub struct Kick {
+    pub pitch: f32,
+    pub sweep: f32,
+    pub decay_ms: f32,
+    tune: f32,
+    phase: f32,
+    sweep_level: f32,
+    sweep_coef: f32,
+    amp: Decay,
+}
+
+impl Kick {
+    const SWEEP_MS: f32 = 30.;
+    const PITCH: f32 = 48.;
+    const SWEEP: f32 = 5.;
+    const DECAY_MS: f32 = 450.;
+
+    pub fn new() -> Kick {
+        Kick { pitch: Kick::PITCH, sweep: Kick::SWEEP, decay_ms: Kick::DECAY_MS, tune: 1., phase: 0., sweep_level: 0., sweep_coef: decay_coef(Kick::SWEEP_MS), amp: Decay::SILENT }
+    }
+}
+
+impl Default for Kick {
+    fn default() -> Kick {
+        Kick::new()
+    }
+}
+
+impl Kick {
+    fn trigger(&mut self, velocity: u8) {
+        self.phase = 0.;
+        self.sweep_level = 1.;
+        self.amp.trigger(velocity_level(velocity), self.decay_ms);
+    }
+
+    fn choke(&mut self) {
+        self.amp.choke();
+    }
+
+    fn is_active(&self) -> bool {
+        self.amp.is_active()
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.is_active() {
+            return 0.;
+        }
+        let freq = self.pitch * self.tune * (1. + (self.sweep - 1.) * self.sweep_level);
+        self.sweep_level *= self.sweep_coef;
+        let out = libm::sinf(2. * PI * self.phase) * self.amp.step();
+        self.phase = wrap_phase(self.phase + freq / SAMPLE_FREQ as f32);
+        out
+    }
+}
+
+// Sine body with highpassed noise, snappy sets the amount of noise
+#[derive(Copy, Clone)]
+pub struct Snare {
+    pub tone: f32,
+    pub snappy: f32,
+    pub decay_ms: f32,
+    tune: f32,
+    phase: f32,
+    body: Decay,
+    snares: Decay,
+    highpass: Biquad,
+    rng: Rand32,
+}
+
+impl Snare {
+    const TONE: f32 = 185.;
+    const SNAPPY: f32 = 0.6;
+    const DECAY_MS: f32 = 200.;
+
+    pub fn new() -> Snare {
+        Snare {
+            tone: Snare::TONE,
+            snappy: Snare::SNAPPY,
+            decay_ms: Snare::DECAY_MS,
+            tune: 1.,
+            phase: 0.,
+            body: Decay::SILENT,
+            snares: Decay::SILENT,
+            highpass: Biquad::highpass(1500., 0.7),
+            rng: Rand32::new(NOISE_SEED),
+        }
+    }
+}
+
+impl Default for Snare {
+    fn default() -> Snare {
+        Snare::new()
+    }
+}
+
+impl Snare {
+    fn trigger(&mut self, velocity: u8) {
+        let level = velocity_level(velocity);
+        self.phase = 0.;
+        self.body.trigger(level * (1. - 0.5 * self.snappy), self.decay_ms / 2.);
+        // Highpassed noise peaks above 1
+        self.snares.trigger(level * self.snappy / 2., self.decay_ms);
+        self.highpass = Biquad::highpass(1500. * self.tune, 0.7);
+    }
+
+    fn choke(&mut self) {
+        self.body.choke();
+        self.snares.choke();
+    }
+
+    fn is_active(&self) -> bool {
+        self.body.is_active() || self.snares.is_active()
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.is_active() {
+            return 0.;
+        }
+        let body = libm::sinf(2. * PI * self.phase) * self.body.step();
+        self.phase = wrap_phase(self.phase + self.tone * self.tune / SAMPLE_FREQ as f32);
+        let snares = self.highpass.step(noise(&mut self.rng)) * self.snares.step();
+        body + snares
+    }
+}
+
+// Six detuned square waves as in the 808, highpassed from tone
+#[derive(Copy, Clone)]
+pub struct HiHat {
+    pub tone: f32,
+    pub decay_ms: f32,
+    tune: f32,
+    // Phases and increments as fractions of 2^32
+    phases: [u32; 6],
+    increments: [u32; 6],
+    highpass: Biquad,
+    amp: Decay,
+}
+
+impl HiHat {
+    const FREQS: [f32; 6] = [205.3, 304.4, 369.6, 522.7, 540., 800.];
+    const TONE: f32 = 7000.;
+    const DECAY_MS: f32 = 80.;
+
+    pub fn new() -> HiHat {
+        HiHat {
+            tone: HiHat::TONE,
+            decay_ms: HiHat::DECAY_MS,
+            tune: 1.,
+            phases: [0; 6],
+            increments: [0; 6],
+            highpass: Biquad::highpass(HiHat::TONE, 0.7),
+            amp: Decay::SILENT,
+        }
+    }
+}
+
+impl Default for HiHat {
+    fn default() -> HiHat {
+        HiHat::new()
+    }
+}
+
+impl HiHat {
+    fn trigger(&mut self, velocity: u8) {
+        self.increments = HiHat::FREQS.map(|freq| (freq * self.tune / SAMPLE_FREQ as f32 * 4_294_967_296.) as u32);
+        self.highpass = Biquad::highpass(self.tone * self.tune, 0.7);
+        self.amp.trigger(velocity_level(velocity), self.decay_ms);
+    }
+
+    fn choke(&mut self) {
+        self.amp.choke();
+    }
+
+    fn is_active(&self) -> bool {
+        self.amp.is_active()
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.is_active() {
+            return 0.;
+        }
+        let mut sum = 0.;
+        for (phase, increment) in self.phases.iter_mut().zip(self.increments.iter()) {
+            *phase = phase.wrapping_add(*increment);
+            sum += if *phase < 1 << 31 { 1. } else { -1. };
+        }
+        // The highpass takes away most of the level
+        self.highpass.step(sum / 3.) * self.amp.step()
+    }
+}
+
+// Bandpassed noise in a few quick bursts and a tail
+#[derive(Copy, Clone)]
+pub struct Clap {
+    pub tone: f32,
+    pub decay_ms: f32,
+    tune: f32,
+    age: u32,
+    level: f32,
+    bursts: Decay,
+    tail: Decay,
+    bandpass: Biquad,
+    rng: Rand32,
+}
+
+impl Clap {
+    const BURSTS: u32 = 3;
+    const BURST_MS: f32 = 10.;
+    const BURST_LEN: u32 = (Clap::BURST_MS * SAMPLE_FREQ as f32 / 1000.) as u32;
+    const TONE: f32 = 1200.;
+    const DECAY_MS: f32 = 250.;
+
+    pub fn new() -> Clap {
+        Clap {
+            tone: Clap::TONE,
+            decay_ms: Clap::DECAY_MS,
+            tune: 1.,
+            age: u32::MAX,
+            level: 0.,
+            bursts: Decay::SILENT,
+            tail: Decay::SILENT,
+            bandpass: Biquad::bandpass(Clap::TONE, 1.5),
+            rng: Rand32::new(NOISE_SEED),
+        }
+    }
+
+    fn in_bursts(&self) -> bool {
+        self.age < Clap::BURSTS * Clap::BURST_LEN
+    }
+}
+
+impl Default for Clap {
+    fn default() -> Clap {
+        Clap::new()
+    }
+}
+
+impl Clap {
+    fn trigger(&mut self, velocity: u8) {
+        self.age = 0;
+        self.level = velocity_level(velocity);
+        self.tail = Decay::SILENT;
+        self.bandpass = Biquad::bandpass(self.tone * self.tune, 1.5);
+    }
+
+    fn choke(&mut self) {
+        self.age = u32::MAX;
+        self.bursts.choke();
+        self.tail.choke();
+    }
+
+    fn is_active(&self) -> bool {
+        self.in_bursts() || self.bursts.is_active() || self.tail.is_active()
+    }
+
+    fn step(&mut self) -> f32 {
+        if !self.is_active() {
+            return 0.;
+        }
+        if self.in_bursts() && self.age.is_multiple_of(Clap::BURST_LEN) {
+            self.bursts.trigger(self.level, Clap::BURST_MS);
+        }
+        if self.age == Clap::BURSTS * Clap::BURST_LEN {
+            self.tail.trigger(self.level / 2., self.decay_ms);
+        }
+        self.age = self.age.saturating_add(1);
+        // The bandpass passes a small part of the noise
+        3. * self.bandpass.step(noise(&mut self.rng)) * (self.bursts.step() + self.tail.step())
+    }
+}
+
+// Dispatches to the drum kinds without boxing
+#[derive(Copy, Clone)]
+pub enum Drum {
+    Kick(Kick),
+    Snare(Snare),
+    HiHat(HiHat),
+    Clap(Clap),
+}
+
+impl Drum {
+    pub fn new(kind: usize) -> Drum {
+        match kind {
+            0 => Drum::Kick(Kick::new()),
+            1 => Drum::Snare(Snare::new()),
+            2 => Drum::HiHat(HiHat::new()),
+            3 => Drum::Clap(Clap::new()),
+            _ => panic!("no drum {}", kind),
+        }
+    }
+
+    pub fn name(kind: usize) -> &'static str {
+        DRUM_NAMES[kind]
+    }
+
+    pub fn gm_note(kind: usize) -> u8 {
+        DRUM_GM_NOTES[kind]
+    }
+
+    // Setting and default of a parameter of the drum
+    fn param_mut(&mut self, param: Param) -> Option<(&mut f32, f32)> {
+        match (self, param) {
+            (Drum::Kick(kick), Param::Tone) => Some((&mut kick.pitch, Kick::PITCH)),
+            (Drum::Kick(kick), Param::Color) => Some((&mut kick.sweep, Kick::SWEEP)),
+            (Drum::Kick(kick), Param::Decay) => Some((&mut kick.decay_ms, Kick::DECAY_MS)),
+            (Drum::Snare(snare), Param::Tone) => Some((&mut snare.tone, Snare::TONE)),
+            (Drum::Snare(snare), Param::Color) => Some((&mut snare.snappy, Snare::SNAPPY)),
+            (Drum::Snare(snare), Param::Decay) => Some((&mut snare.decay_ms, Snare::DECAY_MS)),
+            (Drum::HiHat(hihat), Param::Tone) => Some((&mut hihat.tone, HiHat::TONE)),
+            (Drum::HiHat(hihat), Param::Decay) => Some((&mut hihat.decay_ms, HiHat::DECAY_MS)),
+            (Drum::Clap(clap), Param::Tone) => Some((&mut clap.tone, Clap::TONE)),
+            (Drum::Clap(clap), Param::Decay) => Some((&mut clap.decay_ms, Clap::DECAY_MS)),
+            _ => None,
+        }
+    }
+
+    fn param_ref(&self, param: Param) -> Option<&f32> {
+        match (self, param) {
+            (Drum::Kick(kick), Param::Tone) => Some(&kick.pitch),
+            (Drum::Kick(kick), Param::Color) => Some(&kick.sweep),
+            (Drum::Kick(kick), Param::Decay) => Some(&kick.decay_ms),
+            (Drum::Snare(snare), Param::Tone) => Some(&snare.tone),
+            (Drum::Snare(snare), Param::Color) => Some(&snare.snappy),
+            (Drum::Snare(snare), Param::Decay) => Some(&snare.decay_ms),
+            (Drum::HiHat(hihat), Param::Tone) => Some(&hihat.tone),
+            (Drum::HiHat(hihat), Param::Decay) => Some(&hihat.decay_ms),
+            (Drum::Clap(clap), Param::Tone) => Some(&clap.tone),
+            (Drum::Clap(clap), Param::Decay) => Some(&clap.decay_ms),
+            _ => None,
+        }
+    }
+
+    fn tune(&self) -> f32 {
+        match self {
+            Drum::Kick(kick) => kick.tune,
+            Drum::Snare(snare) => snare.tune,
+            Drum::HiHat(hihat) => hihat.tune,
+            Drum::Clap(clap) => clap.tune,
+        }
+    }
+
+    fn tune_mut(&mut self) -> &mut f32 {
+        match self {
+            Drum::Kick(kick) => &mut kick.tune,
+            Drum::Snare(snare) => &mut snare.tune,
+            Drum::HiHat(hihat) => &mut hihat.tune,
+            Drum::Clap(clap) => &mut clap.tune,
+        }
+    }
+}
+
+fn add_steps(block: &mut [f32], mut step: impl FnMut() -> f32) {
+    for v in block.iter_mut() {
+        *v += step();
+    }
+}
+
+impl Drum {
+    fn trigger(&mut self, velocity: u8) {
+        match self {
+            Drum::Kick(kick) => kick.trigger(velocity),
+            Drum::Snare(snare) => snare.trigger(velocity),
+            Drum::HiHat(hihat) => hihat.trigger(velocity),
+            Drum::Clap(clap) => clap.trigger(velocity),
+        }
+    }
+
+    fn choke(&mut self) {
+        match self {
+            Drum::Kick(kick) => kick.choke(),
+            Drum::Snare(snare) => snare.choke(),
+            Drum::HiHat(hihat) => hihat.choke(),
+            Drum::Clap(clap) => clap.choke(),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        match self {
+            Drum::Kick(kick) => kick.is_active(),
+            Drum::Snare(snare) => snare.is_active(),
+            Drum::HiHat(hihat) => hihat.is_active(),
+            Drum::Clap(clap) => clap.is_active(),
+        }
+    }
+
+    fn render(&mut self, block: &mut [f32]) {
+        match self {
+            Drum::Kick(kick) => add_steps(block, || kick.step()),
+            Drum::Snare(snare) => add_steps(block, || snare.step()),
+            Drum::HiHat(hihat) => add_steps(block, || hihat.step()),
+            Drum::Clap(clap) => add_steps(block, || clap.step()),
+        }
+    }
+
+    fn param(&self, param: Param) -> Option<f32> {
+        match param {
+            Param::Tune => Some(12. * libm::log2f(self.tune())),
+            _ => self.param_ref(param).copied(),
+        }
+    }
+
+    // Tune applies from the next hit
+    fn set_param(&mut self, param: Param, value: Option<f32>) {
+        match param {
+            Param::Tune => *self.tune_mut() = semitones_to_ratio(value.unwrap_or(0.)),
+            _ => {
+                if let Some((setting, default)) = self.param_mut(param) {
+                    *setting = value.unwrap_or(default);
+                }
+            },
+        }
+    }
+}
+
+// A drum plays one hit at a time, a new hit fades out the previous one whatever the retrigger mode
+pub struct DrumVoice {
+    hit: Drum,
+    choked: Drum,
+}
+
+impl DrumVoice {
+    pub fn new(kind: usize) -> DrumVoice {
+        DrumVoice { hit: Drum::new(kind), choked: Drum::new(kind) }
+    }
+}
+
+impl Voice for DrumVoice {
+    fn trigger(&mut self, velocity: u8) {
+        if self.hit.is_active() {
+            self.choked = self.hit;
+            self.choked.choke();
+        }
+        self.hit.trigger(velocity);
+    }
+
+    fn choke(&mut self) {
+        self.hit.choke();
+    }
+
+    fn is_active(&self) -> bool {
+        self.hit.is_active() || self.choked.is_active()
+    }
+
+    fn render(&mut self, block: &mut [f32]) {
+        self.hit.render(block);
+        self.choked.render(block);
+    }
+
+    // The drums are synthesized in float on both paths
+    fn render_q15(&mut self, block: &mut [i32]) {
+        let mut float_block = [0.; 64];
+        for block in block.chunks_mut(float_block.len()) {
+            let float_block = &mut float_block[..block.len()];
+            float_block.fill(0.);
+            self.render(float_block);
+            for (v, &x) in block.iter_mut().zip(float_block.iter()) {
+                *v += (x * 32768.) as i32;
+            }
+        }
+    }
+
+    fn param(&self, param: Param) -> Option<f32> {
+        self.hit.param(param)
+    }
+
+    fn set_param(&mut self, param: Param, value: Option<f32>) {
+        self.hit.set_param(param, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(drum: &mut Drum, velocity: u8) -> Vec<f32> {
+        drum.trigger(velocity);
+        let mut out = Vec::new();
+        while drum.is_active() {
+            let mut v = [0.];
+            drum.render(&mut v);
+            out.push(v[0]);
+        }
+        out
+    }
+
+    fn zero_crossings(audio: &[f32]) -> usize {
+        audio.windows(2).filter(|w| (w[0] < 0.) != (w[1] < 0.)).count()
+    }
+
+    #[test]
+    fn drums_decay_to_silence() {
+        for kind in 0..NUM_DRUMS {
+            let mut drum = Drum::new(kind);
+            let audio = render(&mut drum, 127);
+            let peak = audio.iter().fold(0f32, |peak, x| peak.max(x.abs()));
+            assert!(peak > 0.2 && peak <= 1.2, "{} peaks at {}", Drum::name(kind), peak);
+            // A decay sets the time to -60 dB, silence is at -80 dB
+            assert!(audio.len() < SAMPLE_FREQ as usize, "{} plays {} samples", Drum::name(kind), audio.len());
+
+            drum.set_param(Param::Decay, Some(20.));
+            assert!(render(&mut drum, 127).len() < audio.len());
+        }
+    }
+
+    #[test]
+    fn kick_sweeps_down() {
+        let mut kick = Drum::new(0);
+        let audio = render(&mut kick, 127);
+        let window = SAMPLE_FREQ as usize / 20;
+        assert!(zero_crossings(&audio[..window]) > zero_crossings(&audio[window..2 * window]));
+
+        kick.set_param(Param::Tune, Some(12.));
+        let tuned = render(&mut kick, 127);
+        assert!(zero_crossings(&tuned[window..2 * window]) > zero_crossings(&audio[window..2 * window]));
+    }
+
+    #[test]
+    fn drums_choke() {
+        for kind in 0..NUM_DRUMS {
+            let mut drum = Drum::new(kind);
+            drum.trigger(100);
+            drum.render(&mut [0.; 100]);
+            drum.choke();
+            drum.render(&mut [0.; CHOKE_FADE_LEN as usize]);
+            assert!(!drum.is_active(), "{} plays after choke", Drum::name(kind));
+        }
+    }
+
+    #[test]
+    fn retrigger_fades_previous_hit() {
+        let mut kick = DrumVoice::new(0);
+        kick.trigger(127);
+        kick.render(&mut [0.; 1000]);
+        let mut previous = kick.hit;
+        kick.trigger(127);
+
+        let mut retriggered = [0.; CHOKE_FADE_LEN as usize];
+        let mut continued = [0.; CHOKE_FADE_LEN as usize];
+        kick.render(&mut retriggered);
+        previous.render(&mut continued);
+        // The new hit starts at a zero crossing of the sine, the previous one continues
+        assert!((retriggered[0] - continued[0]).abs() < 1e-6);
+        assert!(continued[0].abs() > 0.1);
+        assert!(!kick.choked.is_active());
+        assert!(kick.is_active());
+    }
+
+    #[test]
+    fn params_restore_defaults() {
+        let mut snare = Drum::new(1);
+        assert_eq!(snare.param(Param::Color), Some(0.6));
+        assert_eq!(snare.param(Param::Attack), None);
+        snare.set_param(Param::Color, Some(0.2));
+        snare.set_param(Param::Tune, Some(-5.));
+        assert_eq!(snare.param(Param::Color), Some(0.2));
+        assert!((snare.param(Param::Tune).unwrap() + 5.).abs() < 1e-4);
+        snare.set_param(Param::Color, None);
+        assert_eq!(snare.param(Param::Color), Some(0.6));
+    }
+}
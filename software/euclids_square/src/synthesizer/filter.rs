@@ -0,0 +1,217 @@
+use core::f32::consts::PI;
+use super::SAMPLE_FREQ;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterMode {
+    Off,
+    Lowpass,
+    Bandpass,
+    Highpass,
+}
+
+// What moves the cutoff of a hit, by up to the given octaves
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FilterMod {
+    None,
+    // Down for softer hits
+    Velocity(u8),
+    // Up at the hit, decaying back to the cutoff
+    Envelope(u8),
+}
+
+// Samples per update of the coefficients while modulated
+const UPDATE_LEN: usize = 16;
+
+const ENVELOPE_MS: f32 = 150.;
+
+// Cutoff below Nyquist, so the coefficients stay stable
+const MAX_CUTOFF: f32 = 0.45 * SAMPLE_FREQ as f32;
+const MIN_CUTOFF: f32 = 20.;
+
+// State below which the filter counts as silent, about -100 dB
+const SILENCE: f32 = 1e-5;
+
+// Damping from resonance 0 to 1, a Q from 0.7 to about 14
+fn damping(resonance: f32) -> f32 {
+    core::f32::consts::SQRT_2 * (1. - 0.95 * resonance)
+}
+
+fn prewarp(cutoff: f32) -> f32 {
+    libm::tanf(PI * cutoff.clamp(MIN_CUTOFF, MAX_CUTOFF) / SAMPLE_FREQ as f32)
+}
+
+// Gain at a frequency, the filter is the bilinear transform of the analog state-variable filter
+pub fn response(mode: FilterMode, cutoff: f32, resonance: f32, freq: f32) -> f32 {
+    let k = damping(resonance);
+    // Frequency on the analog axis relative to the cutoff
+    let x = libm::tanf(PI * freq / SAMPLE_FREQ as f32) / prewarp(cutoff);
+    let denominator = libm::sqrtf((1. - x * x) * (1. - x * x) + (k * x) * (k * x));
+    match mode {
+        FilterMode::Off => 1.,
+        FilterMode::Lowpass => 1. / denominator,
+        FilterMode::Bandpass => k * x / denominator,
+        FilterMode::Highpass => x * x / denominator,
+    }
+}
+
+// Topology preserving transform state-variable filter, which stays stable when the cutoff moves
+#[derive(Debug, Copy, Clone)]
+pub struct Filter {
+    pub mode: FilterMode,
+    // Hz and from 0 to 1
+    pub cutoff: f32,
+    pub resonance: f32,
+    pub modulation: FilterMod,
+    // Of the last hit, from 0 to 1
+    velocity: f32,
+    envelope: f32,
+    state: [f32; 2],
+}
+
+impl Filter {
+    pub const OFF: Filter = Filter {
+        mode: FilterMode::Off,
+        cutoff: MAX_CUTOFF,
+        resonance: 0.,
+        modulation: FilterMod::None,
+        velocity: 1.,
+        envelope: 0.,
+        state: [0.; 2],
+    };
+
+    pub fn trigger(&mut self, velocity: u8) {
+        self.velocity = velocity as f32 / 127.;
+        self.envelope = 1.;
+    }
+
+    // Still ringing from the previous hits, after its input stopped
+    pub fn is_ringing(&self) -> bool {
+        self.mode != FilterMode::Off && self.state.iter().any(|state| state.abs() > SILENCE)
+    }
+
+    pub fn reset(&mut self) {
+        self.state = [0.; 2];
+    }
+
+    fn modulated_cutoff(&self) -> f32 {
+        let octaves = match self.modulation {
+            FilterMod::None => 0.,
+            FilterMod::Velocity(depth) => depth as f32 * (self.velocity - 1.),
+            FilterMod::Envelope(depth) => depth as f32 * self.envelope,
+        };
+        self.cutoff * libm::exp2f(octaves)
+    }
+
+    pub fn process(&mut self, block: &mut [f32]) {
+        if self.mode == FilterMode::Off {
+            return;
+        }
+        let k = damping(self.resonance);
+        for block in block.chunks_mut(UPDATE_LEN) {
+            let g = prewarp(self.modulated_cutoff());
+            let a1 = 1. / (1. + g * (g + k));
+            let a2 = g * a1;
+            let a3 = g * a2;
+            for v in block.iter_mut() {
+                let [ic1eq, ic2eq] = self.state;
+                let v3 = *v - ic2eq;
+                let band = a1 * ic1eq + a2 * v3;
+                let low = ic2eq + a2 * ic1eq + a3 * v3;
+                self.state = [2. * band - ic1eq, 2. * low - ic2eq];
+                *v = match self.mode {
+                    FilterMode::Lowpass => low,
+                    // Normalised to a peak gain of 1
+                    FilterMode::Bandpass => k * band,
+                    FilterMode::Highpass => *v - k * band - low,
+                    FilterMode::Off => *v,
+                };
+            }
+            if let FilterMod::Envelope(_) = self.modulation {
+                self.envelope *= libm::expf(-6.9078 * block.len() as f32 / (ENVELOPE_MS * SAMPLE_FREQ as f32 / 1000.));
+            }
+        }
+    }
+
+    // Filters in float, the fixed point path only pays for it when the filter is on
+    pub fn process_q15(&mut self, block: &mut [i32]) {
+        if self.mode == FilterMode::Off {
+            return;
+        }
+        let mut float_block = [0.; UPDATE_LEN];
+        for block in block.chunks_mut(UPDATE_LEN) {
+            let float_block = &mut float_block[..block.len()];
+            for (x, &v) in float_block.iter_mut().zip(block.iter()) {
+                *x = v as f32 / 32768.;
+            }
+            self.process(float_block);
+            for (v, &x) in block.iter_mut().zip(float_block.iter()) {
+                *v = (x * 32768.) as i32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(mode: FilterMode, cutoff: f32, resonance: f32) -> Filter {
+        Filter { mode, cutoff, resonance, ..Filter::OFF }
+    }
+
+    // Gain of a sine through the filter, after it settles
+    fn measure(filter: &mut Filter, freq: f32) -> f32 {
+        let mut block: Vec<f32> = (0..SAMPLE_FREQ as usize / 5)
+            .map(|i| libm::sinf(2. * PI * freq * i as f32 / SAMPLE_FREQ as f32))
+            .collect();
+        filter.process(&mut block);
+        let settled = &block[block.len() / 2..];
+        let rms = |x: &[f32]| libm::sqrtf(x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32);
+        rms(settled) * core::f32::consts::SQRT_2
+    }
+
+    #[test]
+    fn response_matches_filtering() {
+        for &mode in [FilterMode::Lowpass, FilterMode::Bandpass, FilterMode::Highpass].iter() {
+            for &resonance in [0., 0.5, 0.9].iter() {
+                for &freq in [100., 500., 1000., 2000., 8000.].iter() {
+                    let gain = measure(&mut filter(mode, 1000., resonance), freq);
+                    let expected = response(mode, 1000., resonance, freq);
+                    assert!((gain - expected).abs() < 0.02 * expected.max(1.), "{:?} {} at {} Hz: {} != {}", mode, resonance, freq, gain, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn modes_shape_response() {
+        let gain = |mode, resonance, freq| response(mode, 1000., resonance, freq);
+        // 12 dB per octave away from the cutoff
+        assert!(gain(FilterMode::Lowpass, 0., 100.) > 0.99);
+        assert!(gain(FilterMode::Lowpass, 0., 4000.) < 0.07);
+        assert!(gain(FilterMode::Highpass, 0., 250.) < 0.07);
+        assert!(gain(FilterMode::Highpass, 0., 10000.) > 0.99);
+        assert!((gain(FilterMode::Bandpass, 0.5, 1000.) - 1.).abs() < 1e-3);
+        assert!(gain(FilterMode::Bandpass, 0.5, 250.) < 0.2);
+        // About -3 dB at the cutoff without resonance, a peak with it
+        assert!((gain(FilterMode::Lowpass, 0., 1000.) - core::f32::consts::FRAC_1_SQRT_2).abs() < 1e-3);
+        assert!(gain(FilterMode::Lowpass, 1., 1000.) > 10.);
+        assert_eq!(gain(FilterMode::Off, 0., 4000.), 1.);
+    }
+
+    #[test]
+    fn modulation_moves_cutoff() {
+        let mut soft = Filter { modulation: FilterMod::Velocity(2), ..filter(FilterMode::Lowpass, 4000., 0.) };
+        soft.trigger(32);
+        let mut hard = soft;
+        hard.trigger(127);
+        assert!(measure(&mut soft, 3000.) < 0.5 * measure(&mut hard, 3000.));
+
+        // The envelope opens the filter at the hit and decays in about 150 ms
+        let mut filter = Filter { modulation: FilterMod::Envelope(3), ..filter(FilterMode::Lowpass, 500., 0.) };
+        filter.trigger(127);
+        assert!((filter.modulated_cutoff() - 4000.).abs() < 1.);
+        filter.process(&mut [0.; SAMPLE_FREQ as usize / 5]);
+        assert!(filter.modulated_cutoff() < 505.);
+    }
+}
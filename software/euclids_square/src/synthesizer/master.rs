@@ -0,0 +1,282 @@
+use super::SAMPLE_FREQ;
+
+// Frames per update of the compressor gain
+const CHUNK_LEN: usize = 16;
+
+// Saturation starts at half of full scale
+const KNEE: f32 = 0.5;
+const KNEE_Q15: i64 = 1 << 14;
+
+// Linear up to the knee, then approaching full scale with a continuous slope
+fn soft_clip(x: f32) -> f32 {
+    let a = libm::fabsf(x);
+    if a <= KNEE {
+        return x;
+    }
+    let u = (a - KNEE) / (1. - KNEE);
+    let y = KNEE + (1. - KNEE) * u / (1. + u);
+    if x < 0. { -y } else { y }
+}
+
+fn soft_clip_q15(x: i64) -> i64 {
+    let a = x.abs();
+    if a <= KNEE_Q15 {
+        return x;
+    }
+    let u = (a - KNEE_Q15) * 2;
+    let y = KNEE_Q15 + (KNEE_Q15 * u) / ((1 << 15) + u);
+    if x < 0 { -y } else { y }
+}
+
+fn time_coef(ms: f32, len: usize) -> f32 {
+    libm::expf(-(len as f32) / (ms * SAMPLE_FREQ as f32 / 1000.))
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SoftClip {
+    pub bypass: bool,
+    // Gain into the saturation
+    pub drive: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Bitcrusher {
+    pub bypass: bool,
+    // From 1 to 16, set through set_bits
+    bits: u32,
+    // Holds each sample for this many frames
+    pub downsample: u32,
+    held: [f32; 2],
+    held_q15: [i32; 2],
+    count: u32,
+}
+
+impl Bitcrusher {
+    pub fn bits(&self) -> u32 {
+        self.bits
+    }
+
+    // Clamped to 1 to 16, the shifts of both paths need at least 1 bit and at most the 16 of the DAC
+    pub fn set_bits(&mut self, bits: u32) {
+        self.bits = bits.clamp(1, 16);
+    }
+
+    fn hold(&mut self) -> bool {
+        let is_new = self.count == 0;
+        self.count = (self.count + 1) % self.downsample.max(1);
+        is_new
+    }
+}
+
+// Peak compressor, which turns the level above the threshold down by the ratio
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Compressor {
+    pub bypass: bool,
+    // Linear, full scale is 1
+    pub threshold: f32,
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    pub makeup: f32,
+    level: f32,
+    gain: f32,
+}
+
+impl Compressor {
+    // Gain at the start and end of a chunk with the given peak
+    fn update(&mut self, peak: f32, len: usize) -> (f32, f32) {
+        let ms = if peak > self.level { self.attack_ms } else { self.release_ms };
+        self.level = peak + (self.level - peak) * time_coef(ms, len);
+        let reduction = if self.level > self.threshold {
+            libm::powf(self.threshold / self.level, 1. - 1. / self.ratio)
+        } else {
+            1.
+        };
+        let from = self.gain;
+        self.gain = reduction * self.makeup;
+        (from, self.gain)
+    }
+}
+
+// Effects on the mix: compressor, bitcrusher and soft clip, in that order
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MasterBus {
+    pub compressor: Compressor,
+    pub bitcrusher: Bitcrusher,
+    pub soft_clip: SoftClip,
+}
+
+impl Default for MasterBus {
+    fn default() -> Self {
+        MasterBus {
+            compressor: Compressor {
+                bypass: true,
+                threshold: 0.5,
+                ratio: 4.,
+                attack_ms: 1.,
+                release_ms: 100.,
+                makeup: 1.,
+                level: 0.,
+                gain: 1.,
+            },
+            bitcrusher: Bitcrusher {
+                bypass: true,
+                bits: 8,
+                downsample: 1,
+                held: [0.; 2],
+                held_q15: [0; 2],
+                count: 0,
+            },
+            soft_clip: SoftClip { bypass: true, drive: 1. },
+        }
+    }
+}
+
+impl MasterBus {
+    pub fn process(&mut self, frames: &mut [[f32; 2]]) {
+        if !self.compressor.bypass {
+            for frames in frames.chunks_mut(CHUNK_LEN) {
+                let peak = frames.iter().flatten().fold(0., |peak: f32, &v| peak.max(libm::fabsf(v)));
+                let (from, to) = self.compressor.update(peak, frames.len());
+                let step = (to - from) / frames.len() as f32;
+                for (i, frame) in frames.iter_mut().enumerate() {
+                    let gain = from + step * (i + 1) as f32;
+                    *frame = frame.map(|v| v * gain);
+                }
+            }
+        }
+        if !self.bitcrusher.bypass {
+            let scale = (1 << (self.bitcrusher.bits - 1)) as f32;
+            for frame in frames.iter_mut() {
+                if self.bitcrusher.hold() {
+                    self.bitcrusher.held = frame.map(|v| libm::floorf(v * scale) / scale);
+                }
+                *frame = self.bitcrusher.held;
+            }
+        }
+        if !self.soft_clip.bypass {
+            let drive = self.soft_clip.drive;
+            for frame in frames.iter_mut() {
+                *frame = frame.map(|v| soft_clip(v * drive));
+            }
+        }
+    }
+
+    // Same in Q15, only the compressor gain is computed in float once per chunk
+    pub fn process_q15(&mut self, frames: &mut [[i32; 2]]) {
+        if !self.compressor.bypass {
+            for frames in frames.chunks_mut(CHUNK_LEN) {
+                let peak = frames.iter().flatten().map(|v| v.abs()).max().unwrap_or(0);
+                let (from, to) = self.compressor.update(peak as f32 / 32768., frames.len());
+                let (from, to) = ((from * (1 << 14) as f32) as i64, (to * (1 << 14) as f32) as i64);
+                let len = frames.len() as i64;
+                for (i, frame) in frames.iter_mut().enumerate() {
+                    let gain = from + (to - from) * (i as i64 + 1) / len;
+                    *frame = frame.map(|v| ((v as i64 * gain) >> 14) as i32);
+                }
+            }
+        }
+        if !self.bitcrusher.bypass {
+            let shift = 16 - self.bitcrusher.bits;
+            for frame in frames.iter_mut() {
+                if self.bitcrusher.hold() {
+                    self.bitcrusher.held_q15 = frame.map(|v| (v >> shift) << shift);
+                }
+                *frame = self.bitcrusher.held_q15;
+            }
+        }
+        if !self.soft_clip.bypass {
+            let drive = (self.soft_clip.drive * (1 << 14) as f32) as i64;
+            for frame in frames.iter_mut() {
+                *frame = frame.map(|v| soft_clip_q15((v as i64 * drive) >> 14) as i32);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn soft_clip_saturates() {
+        assert_eq!(soft_clip(0.3), 0.3);
+        assert_eq!(soft_clip(-0.5), -0.5);
+        let mut last = 0.;
+        for i in 1..400 {
+            let y = soft_clip(i as f32 / 100.);
+            assert!(y > last && y < 1.);
+            last = y;
+        }
+        assert!((soft_clip(-2.) + 0.875).abs() < 1e-6);
+        for x in (-100_000..100_000).step_by(7) {
+            let expected = soft_clip(x as f32 / 32768.) * 32768.;
+            assert!((soft_clip_q15(x) as f32 - expected).abs() <= 1., "{}", x);
+        }
+    }
+
+    #[test]
+    fn bitcrusher_quantises_and_holds() {
+        let mut master = MasterBus::default();
+        master.bitcrusher = Bitcrusher { bypass: false, bits: 3, downsample: 2, ..master.bitcrusher };
+        let mut frames = [[0.3, -0.3], [0.9, 0.9], [0.6, 0.1], [0.2, 0.2]];
+        master.process(&mut frames);
+        assert_eq!(frames, [[0.25, -0.5], [0.25, -0.5], [0.5, 0.], [0.5, 0.]]);
+
+        let mut frames_q15 = [[9830, -9830], [29491, 29491], [19660, 3276], [6553, 6553]];
+        master.bitcrusher.count = 0;
+        master.process_q15(&mut frames_q15);
+        assert_eq!(frames_q15, [[8192, -16384], [8192, -16384], [16384, 0], [16384, 0]]);
+    }
+
+    #[test]
+    fn bits_stay_in_range() {
+        let mut master = MasterBus::default();
+        master.bitcrusher.bypass = false;
+        master.bitcrusher.set_bits(0);
+        assert_eq!(master.bitcrusher.bits(), 1);
+        let mut frames_q15 = [[9830, -9830]];
+        master.process_q15(&mut frames_q15);
+        assert_eq!(frames_q15, [[0, -32768]]);
+
+        master.bitcrusher.set_bits(24);
+        assert_eq!(master.bitcrusher.bits(), 16);
+        let mut frames = [[0.3, -0.3]];
+        master.process(&mut frames);
+        assert_eq!(frames, [[libm::floorf(0.3 * 32768.) / 32768., libm::floorf(-0.3 * 32768.) / 32768.]]);
+    }
+
+    #[test]
+    fn compressor_reduces_by_ratio() {
+        let mut master = MasterBus::default();
+        master.compressor.bypass = false;
+        master.compressor.threshold = 0.25;
+        let mut frames = [[1., -1.]; 2048];
+        master.process(&mut frames);
+        // 12 dB over the threshold comes out 3 dB over it
+        let expected = 0.25 * libm::powf(4., 0.25);
+        assert!((frames[2047][0] - expected).abs() < 1e-3, "{}", frames[2047][0]);
+
+        let mut master_q15 = MasterBus {
+            compressor: Compressor { level: 0., gain: 1., ..master.compressor },
+            ..MasterBus::default()
+        };
+        let mut frames_q15 = [[32767, -32767]; 2048];
+        master_q15.process_q15(&mut frames_q15);
+        assert!((frames_q15[2047][0] as f32 / 32768. - expected).abs() < 1e-3);
+
+        // Quiet signals pass
+        master.compressor.level = 0.;
+        let mut frames = [[0.1, 0.1]; 2048];
+        master.process(&mut frames);
+        assert!((frames[2047][0] - 0.1).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bypass_passes_through() {
+        let mut master = MasterBus::default();
+        let mut frames = [[1.5, -0.7], [0.2, 0.]];
+        master.process(&mut frames);
+        assert_eq!(frames, [[1.5, -0.7], [0.2, 0.]]);
+    }
+}
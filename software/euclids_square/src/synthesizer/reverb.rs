@@ -0,0 +1,202 @@
+use array_init::array_init;
+
+// Half of the combs and allpasses of Freeverb, in frames at 44.1 kHz
+const COMB_LENS: [usize; 4] = [1116, 1277, 1422, 1557];
+const ALLPASS_LENS: [usize; 2] = [556, 341];
+// The right channel is a bit longer, which decorrelates it from the left
+const STEREO_SPREAD: usize = 23;
+
+// Samples of the line for both channels, 25 KB
+pub const REVERB_LEN: usize = 2 * (1116 + 1277 + 1422 + 1557 + 556 + 341) + 6 * STEREO_SPREAD;
+
+pub type ReverbLine = [i16; REVERB_LEN];
+
+// Keeps the combs below full scale, they ring up to 1 / (1 - feedback)
+const INPUT_GAIN: f32 = 0.03;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+// Samples converted at once by the fixed point path
+const CHUNK_LEN: usize = 64;
+
+fn to_f32(v: i16) -> f32 {
+    v as f32 / 32768.
+}
+
+// Saturates at full scale
+fn to_i16(v: f32) -> i16 {
+    (v * 32768.) as i16
+}
+
+fn take<'a>(line: &mut &'a mut [i16], len: usize) -> &'a mut [i16] {
+    let len = len.min(line.len());
+    let (taken, rest) = core::mem::take(line).split_at_mut(len);
+    *line = rest;
+    taken
+}
+
+// Feedback comb with a lowpass in the loop
+struct Comb {
+    buffer: &'static mut [i16],
+    pos: usize,
+    lowpass: f32,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32, damping: f32) -> f32 {
+        let output = to_f32(self.buffer[self.pos]);
+        self.lowpass = output + (self.lowpass - output) * damping;
+        self.buffer[self.pos] = to_i16(input + self.lowpass * feedback);
+        self.pos = if self.pos + 1 == self.buffer.len() { 0 } else { self.pos + 1 };
+        output
+    }
+}
+
+// Spreads the echoes of the combs without colouring them
+struct Allpass {
+    buffer: &'static mut [i16],
+    pos: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = to_f32(self.buffer[self.pos]);
+        self.buffer[self.pos] = to_i16(input + delayed * ALLPASS_FEEDBACK);
+        self.pos = if self.pos + 1 == self.buffer.len() { 0 } else { self.pos + 1 };
+        delayed - input
+    }
+}
+
+// Freeverb with four combs per channel, fed by the mono sum of the sends
+pub struct Reverb {
+    // From 0 to 1
+    pub decay: f32,
+    pub damping: f32,
+    combs: [[Comb; 4]; 2],
+    allpasses: [[Allpass; 2]; 2],
+}
+
+impl Default for Reverb {
+    // Without a line, which leaves the sends silent
+    fn default() -> Self {
+        Reverb::new(&mut [])
+    }
+}
+
+impl Reverb {
+    // The line is split over the combs and allpasses, it's either empty or REVERB_LEN long
+    pub fn new(mut line: &'static mut [i16]) -> Self {
+        assert!(line.is_empty() || line.len() == REVERB_LEN, "reverb line of {} samples", line.len());
+        let spread = |channel: usize| if line.is_empty() { 0 } else { channel * STEREO_SPREAD };
+        let (spread_left, spread_right) = (spread(0), spread(1));
+        let mut channel = |spread: usize| {
+            let combs = array_init(|i| Comb { buffer: take(&mut line, COMB_LENS[i] + spread), pos: 0, lowpass: 0. });
+            let allpasses = array_init(|i| Allpass { buffer: take(&mut line, ALLPASS_LENS[i] + spread), pos: 0 });
+            (combs, allpasses)
+        };
+        let (combs_left, allpasses_left) = channel(spread_left);
+        let (combs_right, allpasses_right) = channel(spread_right);
+        Reverb {
+            decay: 0.5,
+            damping: 0.5,
+            combs: [combs_left, combs_right],
+            allpasses: [allpasses_left, allpasses_right],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.combs[0][0].buffer.is_empty()
+    }
+
+    // Adds the reverb of the sends to the frames
+    pub fn process(&mut self, sends: &[[f32; 2]], frames: &mut [[f32; 2]]) {
+        if self.is_empty() {
+            return;
+        }
+        // The ranges of Freeverb
+        let feedback = 0.7 + 0.28 * self.decay;
+        let damping = 0.4 * self.damping;
+        for (frame, send) in frames.iter_mut().zip(sends.iter()) {
+            let input = (send[0] + send[1]) * INPUT_GAIN;
+            for ((v, combs), allpasses) in frame.iter_mut().zip(self.combs.iter_mut()).zip(self.allpasses.iter_mut()) {
+                let mut output = 0.;
+                for comb in combs.iter_mut() {
+                    output += comb.process(input, feedback, damping);
+                }
+                for allpass in allpasses.iter_mut() {
+                    output = allpass.process(output);
+                }
+                *v += output;
+            }
+        }
+    }
+
+    // Processes in float, a chunk at a time
+    pub fn process_q15(&mut self, sends: &[[i32; 2]], frames: &mut [[i32; 2]]) {
+        if self.is_empty() {
+            return;
+        }
+        let mut float_sends = [[0.; 2]; CHUNK_LEN];
+        let mut float_frames = [[0.; 2]; CHUNK_LEN];
+        for (sends, frames) in sends.chunks(CHUNK_LEN).zip(frames.chunks_mut(CHUNK_LEN)) {
+            let float_sends = &mut float_sends[..sends.len()];
+            let float_frames = &mut float_frames[..frames.len()];
+            for (float_send, send) in float_sends.iter_mut().zip(sends.iter()) {
+                *float_send = send.map(|v| v as f32 / 32768.);
+            }
+            float_frames.fill([0.; 2]);
+            self.process(float_sends, float_frames);
+            for (frame, float_frame) in frames.iter_mut().zip(float_frames.iter()) {
+                frame[0] += (float_frame[0] * 32768.) as i32;
+                frame[1] += (float_frame[1] * 32768.) as i32;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reverb(decay: f32, damping: f32) -> Reverb {
+        Reverb { decay, damping, ..Reverb::new(vec![0; REVERB_LEN].leak()) }
+    }
+
+    // Energy of the impulse response per tenth of a second
+    fn energies(reverb: &mut Reverb) -> Vec<f32> {
+        let mut sends = vec![[0.; 2]; 44100];
+        sends[0] = [1., 1.];
+        let mut frames = vec![[0.; 2]; 44100];
+        reverb.process(&sends, &mut frames);
+        frames.chunks(4410).map(|chunk| chunk.iter().flatten().map(|v| v * v).sum()).collect()
+    }
+
+    #[test]
+    fn uses_whole_line() {
+        let reverb = reverb(0.5, 0.5);
+        let len: usize = reverb.combs.iter().flatten().map(|comb| comb.buffer.len())
+            .chain(reverb.allpasses.iter().flatten().map(|allpass| allpass.buffer.len()))
+            .sum();
+        assert_eq!(len, REVERB_LEN);
+        assert!(Reverb::default().is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn rejects_short_line() {
+        Reverb::new(vec![0; REVERB_LEN / 2].leak());
+    }
+
+    #[test]
+    fn decay_lengthens_tail() {
+        let short = energies(&mut reverb(0., 0.5));
+        let long = energies(&mut reverb(1., 0.5));
+        for energies in [&short, &long].iter() {
+            assert!(energies.windows(2).all(|pair| pair[1] < pair[0] || pair[0] == 0.));
+        }
+        // 60 dB down in half a second, then silent instead of cycling in the quantisation
+        assert!(short[5] < 1e-6 * short[0]);
+        assert_eq!(short[9], 0.);
+        // Less than 20 dB down after a second
+        assert!(long[9] > 0.01 * long[0]);
+    }
+}
@@ -0,0 +1,77 @@
+use super::SAMPLE_FREQ;
+
+// Frames to duck fully at a hit, short enough to sound instant without a click
+const ATTACK_LEN: u32 = SAMPLE_FREQ / 500;
+
+// Ducks the other layers when the source layer hits, by more for louder hits
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sidechain {
+    pub source: Option<usize>,
+    // From 0 to 1
+    pub depth: f32,
+    // Time for the ducking to fade by 60 dB
+    pub release_ms: f32,
+    level: f32,
+    peak: f32,
+    attack_left: u32,
+}
+
+impl Default for Sidechain {
+    fn default() -> Self {
+        Sidechain {
+            source: None,
+            depth: 0.5,
+            release_ms: 150.,
+            level: 0.,
+            peak: 0.,
+            attack_left: 0,
+        }
+    }
+}
+
+impl Sidechain {
+    pub fn trigger(&mut self, velocity: u8) {
+        self.peak = velocity as f32 / 127.;
+        self.attack_left = ATTACK_LEN;
+    }
+
+    // Gain of the ducked layers per frame
+    pub fn process(&mut self, gains: &mut [f32]) {
+        let release = libm::expf(-6.9078 / (self.release_ms * SAMPLE_FREQ as f32 / 1000.));
+        for gain in gains.iter_mut() {
+            if self.attack_left > 0 {
+                self.level += (self.peak - self.level) / self.attack_left as f32;
+                self.attack_left -= 1;
+            } else {
+                self.level *= release;
+            }
+            *gain = 1. - self.depth * self.level;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ducks_and_releases() {
+        let mut sidechain = Sidechain { depth: 0.75, release_ms: 100., ..Default::default() };
+        let mut gains = [0.; 4410];
+        sidechain.process(&mut gains);
+        assert!(gains.iter().all(|&gain| gain == 1.));
+
+        sidechain.trigger(127);
+        sidechain.process(&mut gains);
+        // Down linearly to 1 - depth, then back up in the release time
+        assert!(gains[..ATTACK_LEN as usize].windows(2).all(|pair| pair[1] < pair[0]));
+        assert!((gains[ATTACK_LEN as usize - 1] - 0.25).abs() < 1e-6);
+        assert!(gains[ATTACK_LEN as usize..].windows(2).all(|pair| pair[1] > pair[0]));
+        assert!(gains[4409] > 0.998);
+
+        // Softer hits duck less
+        sidechain.trigger(64);
+        sidechain.process(&mut gains);
+        assert!((gains[ATTACK_LEN as usize - 1] - (1. - 0.75 * 64. / 127.)).abs() < 1e-6);
+    }
+}
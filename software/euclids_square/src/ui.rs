@@ -1,8 +1,57 @@
 use core::cmp;
 use arrayvec::ArrayVec;
 use array_init::array_init;
-use debouncr::Edge;
-use crate::inputs::InputEvent;
+use crate::inputs::{InputEvent, RotTurn, Gestures, GestureConfig, Gesture, CHORD_A, CHORD_D};
+use crate::synthesizer::{NUM_SOUNDS, Effect, Output, Retrigger, Routing};
+use crate::synthesizer::delay::Division;
+use crate::synthesizer::filter::{FilterMode, FilterMod};
+
+pub const DEFAULT_TEMPO: usize = 8;
+pub const MAX_VOLUME: usize = 16;
+const UNITY_VOLUME: usize = 8;
+pub const MAX_PAN: usize = 14;
+const CENTER_PAN: usize = 7;
+pub const MAX_TUNE: isize = 12;
+// The sound is trimmed in steps of a sixteenth of its length
+pub const TRIM_STEPS: usize = 16;
+pub const ATTACKS_MS: [u32; 8] = [0, 1, 2, 5, 10, 20, 50, 100];
+// The last plays the sound to its end
+pub const DECAYS_MS: [Option<u32>; 16] = [
+    Some(20), Some(30), Some(40), Some(50), Some(70), Some(100), Some(130), Some(170),
+    Some(220), Some(300), Some(400), Some(500), Some(700), Some(1000), Some(1500), None,
+];
+// Cutoff in steps of 0.6 octaves from 30 Hz, and resonance
+pub const MAX_CUTOFF: usize = 15;
+pub const MAX_RESONANCE: usize = 15;
+pub const FILTER_MODES: [FilterMode; 4] = [FilterMode::Off, FilterMode::Lowpass, FilterMode::Bandpass, FilterMode::Highpass];
+pub const FILTER_MODS: [FilterMod; 5] = [
+    FilterMod::None,
+    FilterMod::Velocity(2),
+    FilterMod::Velocity(4),
+    FilterMod::Envelope(2),
+    FilterMod::Envelope(4),
+];
+pub const MAX_SEND: usize = 8;
+pub const DELAY_DIVISIONS: [Division; 6] = [
+    Division::Sixteenth,
+    Division::EighthTriplet,
+    Division::Eighth,
+    Division::DottedEighth,
+    Division::Quarter,
+    Division::DottedQuarter,
+];
+// Feedback in sixteenths, so the echoes always die out
+pub const MAX_FEEDBACK: usize = 15;
+pub const MAX_DAMPING: usize = 15;
+pub const MAX_DECAY: usize = 15;
+pub const MAX_DEPTH: usize = 15;
+pub const RELEASES_MS: [u32; 8] = [50, 80, 120, 150, 200, 300, 400, 600];
+// Compressor threshold in 3 dB steps, bits taken off by the bitcrusher, frames it holds and drive into the soft clip
+pub const MAX_COMPRESS: usize = 8;
+pub const MAX_CRUSH: usize = 15;
+pub const MAX_DOWNSAMPLE: usize = 16;
+pub const MAX_DRIVE: usize = 8;
+const UNITY_DRIVE: usize = 4;
 
 #[derive(Debug, Clone)]
 pub struct UiState<const NUM_LAYERS: usize> {
@@ -11,7 +60,14 @@ pub struct UiState<const NUM_LAYERS: usize> {
     pub active_layer: usize,
     pub time_since_last_action: usize,
     pub layers: [LayerState; NUM_LAYERS],
+    // Route each layer to its own DAC output instead of the stereo mix
+    pub independent_outputs: bool,
+    pub delay: DelayState,
+    pub reverb: ReverbState,
+    pub sidechain: SidechainState,
+    pub master: MasterState,
     pub view: ViewState,
+    gestures: Gestures,
 }
 
 #[derive(Debug, Clone)]
@@ -21,6 +77,85 @@ pub enum ViewState {
     Sound,
     Tempo,
     Volume,
+    Pan,
+    Output,
+    Retrigger,
+    Tune,
+    Reverse,
+    Trim,
+    Attack,
+    Decay,
+    Filter,
+    FilterMod,
+    DelaySend,
+    DelayTime,
+    DelayFeedback,
+    DelayDamping,
+    ReverbSend,
+    ReverbDecay,
+    ReverbDamping,
+    SidechainSource,
+    SidechainDepth,
+    SidechainRelease,
+    MasterCompress,
+    MasterCrush,
+    MasterDownsample,
+    MasterDrive,
+}
+
+// Shared by the layers, which each set their send
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DelayState {
+    pub division: Division,
+    pub feedback: usize,
+    pub damping: usize,
+}
+
+impl Default for DelayState {
+    fn default() -> Self {
+        Self { division: Division::Eighth, feedback: 6, damping: 6 }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ReverbState {
+    pub decay: usize,
+    pub damping: usize,
+}
+
+impl Default for ReverbState {
+    fn default() -> Self {
+        Self { decay: 8, damping: 8 }
+    }
+}
+
+// The layers other than the source duck when it hits
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct SidechainState {
+    pub source: Option<usize>,
+    pub depth: usize,
+    pub release_ms: u32,
+}
+
+impl Default for SidechainState {
+    fn default() -> Self {
+        Self { source: None, depth: 8, release_ms: 150 }
+    }
+}
+
+// The effects of the master bus, each bypassed at 0, or the bitcrusher at 0 crush and 1 frame
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MasterState {
+    pub compress: usize,
+    pub crush: usize,
+    pub downsample: usize,
+    pub drive: usize,
+}
+
+impl Default for MasterState {
+    fn default() -> Self {
+        Self { compress: 0, crush: 0, downsample: 1, drive: 0 }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -30,97 +165,355 @@ pub struct LayerState {
     pub length: usize,
     pub hits: usize,
     pub shift: isize,
+    pub accents: usize,
+    pub pan: usize,
+    pub output: Output,
+    pub retrigger: Retrigger,
+    // Semitones
+    pub tune: isize,
+    pub reverse: bool,
+    // In TRIM_STEPS
+    pub start: usize,
+    pub end: usize,
+    pub attack_ms: u32,
+    pub decay_ms: Option<u32>,
+    pub filter: FilterMode,
+    pub cutoff: usize,
+    pub resonance: usize,
+    pub filter_mod: FilterMod,
+    pub delay_send: usize,
+    pub reverb_send: usize,
+}
+
+impl Default for LayerState {
+    fn default() -> Self {
+        Self {
+            sound: 0,
+            volume: UNITY_VOLUME,
+            length: 16,
+            hits: 0,
+            shift: 0,
+            accents: 0,
+            pan: CENTER_PAN,
+            output: Output::Both,
+            retrigger: Retrigger::Choke,
+            tune: 0,
+            reverse: false,
+            start: 0,
+            end: TRIM_STEPS,
+            attack_ms: 0,
+            decay_ms: None,
+            filter: FilterMode::Off,
+            cutoff: MAX_CUTOFF,
+            resonance: 0,
+            filter_mod: FilterMod::None,
+            delay_send: 0,
+            reverb_send: 0,
+        }
+    }
 }
 
 impl<const NUM_LAYERS: usize> Default for UiState<NUM_LAYERS> {
     fn default() -> Self {
         Self {
             is_playing: true,
-            tempo: 8,
+            tempo: DEFAULT_TEMPO,
             active_layer: 0,
             time_since_last_action: 0,
             layers: array_init(|i| LayerState {
                 sound: i,
-                volume: 8,
-                length: 16,
                 hits: (if i == 0 { 1 } else { 0 }),
-                shift: 0,
+                ..Default::default()
             }),
+            independent_outputs: false,
+            delay: Default::default(),
+            reverb: Default::default(),
+            sidechain: Default::default(),
+            master: Default::default(),
             view: ViewState::Player,
+            gestures: Gestures::new([
+                GestureConfig::default(),
+                GestureConfig::default(),
+                // Double click goes back a layer
+                GestureConfig { double_click_ms: Some(250), ..Default::default() },
+                GestureConfig { double_click_ms: Some(250), ..Default::default() },
+            ]),
         }
     }
 }
 
 pub enum OutputEvent {
     LayerUpdate (usize, LayerState),
+    SoundUpdate (usize, usize),
+    VolumeUpdate (usize, usize),
+    PanUpdate (usize, usize),
+    // The routing of UiState::routing changed
+    RoutingUpdate,
+    RetriggerUpdate (usize, Retrigger),
+    TuneUpdate (usize, isize),
+    ReverseUpdate (usize, bool),
+    // Start and end
+    TrimUpdate (usize, usize, usize),
+    // Attack and decay in ms
+    EnvelopeUpdate (usize, u32, Option<u32>),
+    // Mode, cutoff, resonance and modulation
+    FilterUpdate (usize, FilterMode, usize, usize, FilterMod),
+    SendUpdate (usize, Effect, usize),
+    DelayUpdate (DelayState),
+    ReverbUpdate (ReverbState),
+    SidechainUpdate (SidechainState),
+    MasterUpdate (MasterState),
     IsPlaying (bool),
     TempoUpdate (usize),
+    DumpMidi,
+}
+
+pub fn tempo_to_bpm(tempo: usize) -> u32 {
+    40 + 10 * tempo as u32
+}
+
+pub fn volume_to_gain(volume: usize) -> f32 {
+    volume as f32 / UNITY_VOLUME as f32
+}
+
+pub fn trim_to_fraction(trim: usize) -> f32 {
+    trim as f32 / TRIM_STEPS as f32
+}
+
+pub fn cutoff_to_hz(cutoff: usize) -> f32 {
+    30. * libm::exp2f(0.6 * cutoff as f32)
+}
+
+pub fn resonance_to_amount(resonance: usize) -> f32 {
+    resonance as f32 / MAX_RESONANCE as f32
+}
+
+pub fn send_to_gain(send: usize) -> f32 {
+    send as f32 / MAX_SEND as f32
+}
+
+pub fn feedback_to_amount(feedback: usize) -> f32 {
+    feedback as f32 / (MAX_FEEDBACK + 1) as f32
+}
+
+pub fn damping_to_amount(damping: usize) -> f32 {
+    damping as f32 / MAX_DAMPING as f32
+}
+
+pub fn decay_to_amount(decay: usize) -> f32 {
+    decay as f32 / MAX_DECAY as f32
+}
+
+pub fn depth_to_amount(depth: usize) -> f32 {
+    depth as f32 / MAX_DEPTH as f32
+}
+
+// Linear, from full scale down to -24 dB
+pub fn compress_to_threshold(compress: usize) -> f32 {
+    libm::powf(10., -3. * compress as f32 / 20.)
+}
+
+pub fn crush_to_bits(crush: usize) -> u32 {
+    16 - crush as u32
+}
+
+pub fn drive_to_gain(drive: usize) -> f32 {
+    drive as f32 / UNITY_DRIVE as f32
+}
+
+// Position from -1 for left to 1 for right
+pub fn pan_to_position(pan: usize) -> f32 {
+    (pan as f32 - CENTER_PAN as f32) / CENTER_PAN as f32
 }
 
 fn clamp<T: Ord>(v: T, min: T, max: T) -> T {
     cmp::max(min, cmp::min(v, max))
 }
 
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Page {
+    Main,
+    Shift,
+    Voice,
+    Envelope,
+    Filter,
+    Delay,
+    Reverb,
+    Sidechain,
+    Master,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Param {
+    Shift,
+    Tempo,
+    Length,
+    Hits,
+    Sound,
+    Volume,
+    Pan,
+    Accents,
+    Retrigger,
+    Tune,
+    Reverse,
+    Start,
+    End,
+    Attack,
+    Decay,
+    Filter,
+    Cutoff,
+    Resonance,
+    FilterMod,
+    DelaySend,
+    DelayTime,
+    DelayFeedback,
+    DelayDamping,
+    ReverbSend,
+    ReverbDecay,
+    ReverbDamping,
+    SidechainSource,
+    SidechainDepth,
+    SidechainRelease,
+    MasterCompress,
+    MasterCrush,
+    MasterDownsample,
+    MasterDrive,
+}
+
+// Order in which the pan encoder selects the outputs
+const OUTPUTS: [Output; 3] = [Output::Out1, Output::Both, Output::Out2];
+
+pub const RETRIGGERS: [Retrigger; 6] = [
+    Retrigger::Choke,
+    Retrigger::Poly(2),
+    Retrigger::Poly(3),
+    Retrigger::Poly(4),
+    Retrigger::Group(0),
+    Retrigger::Group(1),
+];
+
+impl Param {
+    // Selections step one at a time, regardless of how fast the encoder turns
+    fn is_accelerated(self) -> bool {
+        !matches!(self, Param::Shift | Param::Sound | Param::Pan | Param::Retrigger | Param::Tune | Param::Reverse | Param::Attack | Param::Decay | Param::Filter | Param::FilterMod | Param::DelayTime | Param::SidechainSource | Param::SidechainRelease)
+    }
+
+    fn is_sequence(self) -> bool {
+        matches!(self, Param::Shift | Param::Length | Param::Hits | Param::Accents)
+    }
+}
+
+impl Page {
+    // Parameters controlled by encoders A to D
+    pub fn params(self) -> [Option<Param>; 4] {
+        match self {
+            Page::Main => [Some(Param::Shift), Some(Param::Tempo), Some(Param::Length), Some(Param::Hits)],
+            Page::Shift => [Some(Param::Sound), Some(Param::Volume), Some(Param::Pan), Some(Param::Accents)],
+            Page::Voice => [Some(Param::Retrigger), Some(Param::Tune), Some(Param::Reverse), None],
+            Page::Envelope => [Some(Param::Start), Some(Param::End), Some(Param::Attack), Some(Param::Decay)],
+            Page::Filter => [Some(Param::Filter), Some(Param::Cutoff), Some(Param::Resonance), Some(Param::FilterMod)],
+            Page::Delay => [Some(Param::DelaySend), Some(Param::DelayTime), Some(Param::DelayFeedback), Some(Param::DelayDamping)],
+            Page::Reverb => [Some(Param::ReverbSend), Some(Param::ReverbDecay), Some(Param::ReverbDamping), None],
+            Page::Sidechain => [Some(Param::SidechainSource), Some(Param::SidechainDepth), Some(Param::SidechainRelease), None],
+            Page::Master => [Some(Param::MasterCompress), Some(Param::MasterCrush), Some(Param::MasterDownsample), Some(Param::MasterDrive)],
+        }
+    }
+}
+
 impl<const NUM_LAYERS: usize> UiState<NUM_LAYERS> {
-    pub fn update(&mut self, input: InputEvent) -> ArrayVec<OutputEvent, 3> {
+    pub fn page(&self) -> Page {
+        // Holding a switch selects another function of the encoders, or two for the effects
+        if self.gestures.is_held(0) && self.gestures.is_held(1) {
+            Page::Delay
+        } else if self.gestures.is_held(0) && self.gestures.is_held(2) {
+            Page::Reverb
+        } else if self.gestures.is_held(1) && self.gestures.is_held(2) {
+            Page::Sidechain
+        } else if self.gestures.is_held(2) && self.gestures.is_held(3) {
+            Page::Master
+        } else if self.gestures.is_held(0) {
+            Page::Shift
+        } else if self.gestures.is_held(1) {
+            Page::Filter
+        } else if self.gestures.is_held(2) {
+            Page::Envelope
+        } else if self.gestures.is_held(3) {
+            Page::Voice
+        } else {
+            Page::Main
+        }
+    }
+
+    pub fn routing(&self) -> Routing<NUM_LAYERS> {
+        if self.independent_outputs {
+            Routing::Independent(array_init(|i| self.layers[i].output))
+        } else {
+            Routing::Stereo
+        }
+    }
+
+    pub fn update(&mut self, input: InputEvent) -> ArrayVec<OutputEvent, 8> {
         let mut output_events = ArrayVec::new();
         self.time_since_last_action += 1;
 
-        // Switch layer
-        if input.switch_c.edge == Some(Edge::Rising) {
-            self.active_layer = (self.active_layer+1) % NUM_LAYERS;
-            self.view = ViewState::Sequencer;
-            self.time_since_last_action = 0;
+        let turns = [input.rot_a, input.rot_b, input.rot_c, input.rot_d];
+        let turned = turns.iter().any(Option::is_some);
+        let gestures = self.gestures.update(&input);
+        // A switch held while turning an encoder only acts as modifier
+        if turned {
+            for switch in 0..4 {
+                self.gestures.cancel(switch);
+            }
         }
 
-        // Play / pause
-        if input.switch_b.edge == Some(Edge::Rising) {
-            self.is_playing = !self.is_playing;
-            output_events.push(OutputEvent::IsPlaying(self.is_playing));
+        match gestures.switches[1] {
+            // Play / pause
+            Some(Gesture::Click) => {
+                self.is_playing = !self.is_playing;
+                output_events.push(OutputEvent::IsPlaying(self.is_playing));
+            },
+            // Restart from the first step, on release so that holding B to edit the filter doesn't restart
+            Some(Gesture::Held(_)) => {
+                self.is_playing = true;
+                output_events.push(OutputEvent::IsPlaying(self.is_playing));
+            },
+            _ => (),
         }
 
-        // Tempo
-        if let Some(dir) = input.rot_b {
-            self.tempo = clamp(self.tempo as isize + isize::from(dir), 1, 16) as usize;
-            self.view = ViewState::Tempo;
-            self.time_since_last_action = 0;
-            output_events.push(OutputEvent::IsPlaying(self.is_playing));
+        // Switch layer
+        match gestures.switches[2] {
+            Some(Gesture::Click) => {
+                self.active_layer = (self.active_layer + 1) % NUM_LAYERS;
+                self.view = ViewState::Sequencer;
+                self.time_since_last_action = 0;
+            },
+            Some(Gesture::DoubleClick) => {
+                self.active_layer = (self.active_layer + NUM_LAYERS - 1) % NUM_LAYERS;
+                self.view = ViewState::Sequencer;
+                self.time_since_last_action = 0;
+            },
+            _ => (),
         }
 
-        // Sound
-        if let Some(_dir) = input.rot_b {
-            // TODO
+        // Toggle between the stereo mix and independent outputs, on a double click so
+        // that holding D to look at the voice doesn't toggle it
+        if let Some(Gesture::DoubleClick) = gestures.switches[3] {
+            self.independent_outputs = !self.independent_outputs;
+            self.view = ViewState::Output;
+            self.time_since_last_action = 0;
+            output_events.push(OutputEvent::RoutingUpdate);
         }
 
-        // Volume
-        if let Some(_dir) = input.rot_b {
-            // TODO
+        // Export the pattern
+        if gestures.chord == Some(CHORD_A | CHORD_D) {
+            output_events.push(OutputEvent::DumpMidi);
         }
 
-        // Sequencer actions
-        if input.rot_a.is_some() || input.rot_c.is_some() || input.rot_d.is_some() {
-            let layer = &mut self.layers[self.active_layer];
-            // Shift
-            if let Some(dir) = input.rot_a {
-                layer.shift += isize::from(dir);
-            }
-
-            // Length
-            if let Some(dir) = input.rot_c {
-                let len = layer.length as isize + isize::from(dir);
-                layer.length = clamp(len, 1, 16) as usize;
-                layer.hits = cmp::min(layer.hits, layer.length);
+        for (param, turn) in self.page().params().iter().zip(turns.iter()) {
+            if let (Some(param), Some(turn)) = (param, turn) {
+                self.update_param(*param, *turn, &mut output_events);
+                self.time_since_last_action = 0;
             }
-            
-            // Hits
-            if let Some(dir) = input.rot_d {
-                let hits = layer.hits as isize + isize::from(dir);
-                let hits = clamp(hits, 0, layer.length as isize) as usize;
-                layer.hits = hits;
-            }
-            self.view = ViewState::Sequencer;
-            self.time_since_last_action = 0;
-            output_events.push(OutputEvent::LayerUpdate(self.active_layer, layer.clone()));
         }
 
         // Back to Player after inaction
@@ -131,4 +524,500 @@ impl<const NUM_LAYERS: usize> UiState<NUM_LAYERS> {
 
         output_events
     }
-}
\ No newline at end of file
+
+    fn update_param(&mut self, param: Param, turn: RotTurn, output_events: &mut ArrayVec<OutputEvent, 8>) {
+        let layer = &mut self.layers[self.active_layer];
+        let delta = if param.is_accelerated() { isize::from(turn) } else { isize::from(turn.dir) };
+        match param {
+            Param::Tempo => {
+                self.tempo = clamp(self.tempo as isize + delta, 1, 16) as usize;
+                self.view = ViewState::Tempo;
+                output_events.push(OutputEvent::TempoUpdate(self.tempo));
+            },
+            Param::Sound => {
+                let sound = layer.sound as isize + delta;
+                layer.sound = sound.rem_euclid(NUM_SOUNDS as isize) as usize;
+                self.view = ViewState::Sound;
+                output_events.push(OutputEvent::SoundUpdate(self.active_layer, layer.sound));
+            },
+            Param::Volume => {
+                let volume = layer.volume as isize + delta;
+                layer.volume = clamp(volume, 0, MAX_VOLUME as isize) as usize;
+                self.view = ViewState::Volume;
+                output_events.push(OutputEvent::VolumeUpdate(self.active_layer, layer.volume));
+            },
+            // Selects the output instead when they are independent
+            Param::Pan if self.independent_outputs => {
+                let i = OUTPUTS.iter().position(|&output| output == layer.output).unwrap_or(1) as isize + delta;
+                layer.output = OUTPUTS[clamp(i, 0, OUTPUTS.len() as isize - 1) as usize];
+                self.view = ViewState::Output;
+                output_events.push(OutputEvent::RoutingUpdate);
+            },
+            Param::Pan => {
+                let pan = layer.pan as isize + delta;
+                layer.pan = clamp(pan, 0, MAX_PAN as isize) as usize;
+                self.view = ViewState::Pan;
+                output_events.push(OutputEvent::PanUpdate(self.active_layer, layer.pan));
+            },
+            Param::Retrigger => {
+                let i = RETRIGGERS.iter().position(|&retrigger| retrigger == layer.retrigger).unwrap_or(0) as isize + delta;
+                layer.retrigger = RETRIGGERS[clamp(i, 0, RETRIGGERS.len() as isize - 1) as usize];
+                self.view = ViewState::Retrigger;
+                output_events.push(OutputEvent::RetriggerUpdate(self.active_layer, layer.retrigger));
+            },
+            Param::Tune => {
+                layer.tune = clamp(layer.tune + delta, -MAX_TUNE, MAX_TUNE);
+                self.view = ViewState::Tune;
+                output_events.push(OutputEvent::TuneUpdate(self.active_layer, layer.tune));
+            },
+            // Clockwise plays backwards
+            Param::Reverse => {
+                layer.reverse = delta > 0;
+                self.view = ViewState::Reverse;
+                output_events.push(OutputEvent::ReverseUpdate(self.active_layer, layer.reverse));
+            },
+            Param::Start => {
+                layer.start = clamp(layer.start as isize + delta, 0, layer.end as isize - 1) as usize;
+                self.view = ViewState::Trim;
+                output_events.push(OutputEvent::TrimUpdate(self.active_layer, layer.start, layer.end));
+            },
+            Param::End => {
+                layer.end = clamp(layer.end as isize + delta, layer.start as isize + 1, TRIM_STEPS as isize) as usize;
+                self.view = ViewState::Trim;
+                output_events.push(OutputEvent::TrimUpdate(self.active_layer, layer.start, layer.end));
+            },
+            Param::Attack => {
+                let i = ATTACKS_MS.iter().position(|&attack| attack == layer.attack_ms).unwrap_or(0) as isize + delta;
+                layer.attack_ms = ATTACKS_MS[clamp(i, 0, ATTACKS_MS.len() as isize - 1) as usize];
+                self.view = ViewState::Attack;
+                output_events.push(OutputEvent::EnvelopeUpdate(self.active_layer, layer.attack_ms, layer.decay_ms));
+            },
+            Param::Decay => {
+                let i = DECAYS_MS.iter().position(|&decay| decay == layer.decay_ms).unwrap_or(DECAYS_MS.len() - 1) as isize + delta;
+                layer.decay_ms = DECAYS_MS[clamp(i, 0, DECAYS_MS.len() as isize - 1) as usize];
+                self.view = ViewState::Decay;
+                output_events.push(OutputEvent::EnvelopeUpdate(self.active_layer, layer.attack_ms, layer.decay_ms));
+            },
+            Param::Filter => {
+                let i = FILTER_MODES.iter().position(|&mode| mode == layer.filter).unwrap_or(0) as isize + delta;
+                layer.filter = FILTER_MODES[clamp(i, 0, FILTER_MODES.len() as isize - 1) as usize];
+                self.view = ViewState::Filter;
+                output_events.push(OutputEvent::FilterUpdate(self.active_layer, layer.filter, layer.cutoff, layer.resonance, layer.filter_mod));
+            },
+            Param::Cutoff => {
+                layer.cutoff = clamp(layer.cutoff as isize + delta, 0, MAX_CUTOFF as isize) as usize;
+                self.view = ViewState::Filter;
+                output_events.push(OutputEvent::FilterUpdate(self.active_layer, layer.filter, layer.cutoff, layer.resonance, layer.filter_mod));
+            },
+            Param::Resonance => {
+                layer.resonance = clamp(layer.resonance as isize + delta, 0, MAX_RESONANCE as isize) as usize;
+                self.view = ViewState::Filter;
+                output_events.push(OutputEvent::FilterUpdate(self.active_layer, layer.filter, layer.cutoff, layer.resonance, layer.filter_mod));
+            },
+            Param::FilterMod => {
+                let i = FILTER_MODS.iter().position(|&modulation| modulation == layer.filter_mod).unwrap_or(0) as isize + delta;
+                layer.filter_mod = FILTER_MODS[clamp(i, 0, FILTER_MODS.len() as isize - 1) as usize];
+                self.view = ViewState::FilterMod;
+                output_events.push(OutputEvent::FilterUpdate(self.active_layer, layer.filter, layer.cutoff, layer.resonance, layer.filter_mod));
+            },
+            Param::DelaySend => {
+                layer.delay_send = clamp(layer.delay_send as isize + delta, 0, MAX_SEND as isize) as usize;
+                self.view = ViewState::DelaySend;
+                output_events.push(OutputEvent::SendUpdate(self.active_layer, Effect::Delay, layer.delay_send));
+            },
+            Param::DelayTime => {
+                let i = DELAY_DIVISIONS.iter().position(|&division| division == self.delay.division).unwrap_or(0) as isize + delta;
+                self.delay.division = DELAY_DIVISIONS[clamp(i, 0, DELAY_DIVISIONS.len() as isize - 1) as usize];
+                self.view = ViewState::DelayTime;
+                output_events.push(OutputEvent::DelayUpdate(self.delay));
+            },
+            Param::DelayFeedback => {
+                self.delay.feedback = clamp(self.delay.feedback as isize + delta, 0, MAX_FEEDBACK as isize) as usize;
+                self.view = ViewState::DelayFeedback;
+                output_events.push(OutputEvent::DelayUpdate(self.delay));
+            },
+            Param::DelayDamping => {
+                self.delay.damping = clamp(self.delay.damping as isize + delta, 0, MAX_DAMPING as isize) as usize;
+                self.view = ViewState::DelayDamping;
+                output_events.push(OutputEvent::DelayUpdate(self.delay));
+            },
+            Param::ReverbSend => {
+                layer.reverb_send = clamp(layer.reverb_send as isize + delta, 0, MAX_SEND as isize) as usize;
+                self.view = ViewState::ReverbSend;
+                output_events.push(OutputEvent::SendUpdate(self.active_layer, Effect::Reverb, layer.reverb_send));
+            },
+            Param::ReverbDecay => {
+                self.reverb.decay = clamp(self.reverb.decay as isize + delta, 0, MAX_DECAY as isize) as usize;
+                self.view = ViewState::ReverbDecay;
+                output_events.push(OutputEvent::ReverbUpdate(self.reverb));
+            },
+            Param::ReverbDamping => {
+                self.reverb.damping = clamp(self.reverb.damping as isize + delta, 0, MAX_DAMPING as isize) as usize;
+                self.view = ViewState::ReverbDamping;
+                output_events.push(OutputEvent::ReverbUpdate(self.reverb));
+            },
+            // Off, then the layers
+            Param::SidechainSource => {
+                let i = self.sidechain.source.map_or(0, |source| source + 1) as isize + delta;
+                self.sidechain.source = match clamp(i, 0, NUM_LAYERS as isize) {
+                    0 => None,
+                    i => Some(i as usize - 1),
+                };
+                self.view = ViewState::SidechainSource;
+                output_events.push(OutputEvent::SidechainUpdate(self.sidechain));
+            },
+            Param::SidechainDepth => {
+                self.sidechain.depth = clamp(self.sidechain.depth as isize + delta, 0, MAX_DEPTH as isize) as usize;
+                self.view = ViewState::SidechainDepth;
+                output_events.push(OutputEvent::SidechainUpdate(self.sidechain));
+            },
+            Param::SidechainRelease => {
+                let i = RELEASES_MS.iter().position(|&release| release == self.sidechain.release_ms).unwrap_or(0) as isize + delta;
+                self.sidechain.release_ms = RELEASES_MS[clamp(i, 0, RELEASES_MS.len() as isize - 1) as usize];
+                self.view = ViewState::SidechainRelease;
+                output_events.push(OutputEvent::SidechainUpdate(self.sidechain));
+            },
+            Param::MasterCompress => {
+                self.master.compress = clamp(self.master.compress as isize + delta, 0, MAX_COMPRESS as isize) as usize;
+                self.view = ViewState::MasterCompress;
+                output_events.push(OutputEvent::MasterUpdate(self.master));
+            },
+            Param::MasterCrush => {
+                self.master.crush = clamp(self.master.crush as isize + delta, 0, MAX_CRUSH as isize) as usize;
+                self.view = ViewState::MasterCrush;
+                output_events.push(OutputEvent::MasterUpdate(self.master));
+            },
+            Param::MasterDownsample => {
+                self.master.downsample = clamp(self.master.downsample as isize + delta, 1, MAX_DOWNSAMPLE as isize) as usize;
+                self.view = ViewState::MasterDownsample;
+                output_events.push(OutputEvent::MasterUpdate(self.master));
+            },
+            Param::MasterDrive => {
+                self.master.drive = clamp(self.master.drive as isize + delta, 0, MAX_DRIVE as isize) as usize;
+                self.view = ViewState::MasterDrive;
+                output_events.push(OutputEvent::MasterUpdate(self.master));
+            },
+            Param::Shift => {
+                layer.shift += delta;
+            },
+            Param::Length => {
+                let len = layer.length as isize + delta;
+                layer.length = clamp(len, 1, 16) as usize;
+                layer.hits = cmp::min(layer.hits, layer.length);
+            },
+            Param::Hits => {
+                let hits = layer.hits as isize + delta;
+                layer.hits = clamp(hits, 0, layer.length as isize) as usize;
+            },
+            Param::Accents => {
+                let accents = layer.accents as isize + delta;
+                layer.accents = clamp(accents, 0, layer.hits as isize) as usize;
+            },
+        }
+
+        if param.is_sequence() {
+            let layer = &mut self.layers[self.active_layer];
+            layer.accents = cmp::min(layer.accents, layer.hits);
+            self.view = ViewState::Sequencer;
+            output_events.push(OutputEvent::LayerUpdate(self.active_layer, layer.clone()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inputs::Inputs;
+    use crate::hal::mock::MockInputPins;
+
+    fn poll(ui: &mut UiState<3>, inputs: &mut Inputs, pins: &MockInputPins, ms: usize) {
+        for _ in 0..ms {
+            ui.update(inputs.update(pins));
+        }
+    }
+
+    // Turns encoder `i` one detent clockwise
+    fn turn_cw(ui: &mut UiState<3>, inputs: &mut Inputs, pins: &mut MockInputPins, i: usize) {
+        for &state in &[(true, false), (true, true), (false, true), (false, false)] {
+            pins.encoders[i] = state;
+            poll(ui, inputs, pins, 20);
+        }
+    }
+
+    // Turns encoder `i` one detent counter-clockwise
+    fn turn_ccw(ui: &mut UiState<3>, inputs: &mut Inputs, pins: &mut MockInputPins, i: usize) {
+        for &state in &[(false, true), (true, true), (true, false), (false, false)] {
+            pins.encoders[i] = state;
+            poll(ui, inputs, pins, 20);
+        }
+    }
+
+    #[test]
+    fn volume_is_unity_in_the_middle() {
+        assert_eq!(volume_to_gain(0), 0.);
+        assert_eq!(volume_to_gain(UNITY_VOLUME), 1.);
+        assert_eq!(volume_to_gain(MAX_VOLUME), 2.);
+    }
+
+    #[test]
+    fn sound_wraps_around() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[0] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        turn_ccw(&mut ui, &mut inputs, &mut pins, 0);
+        assert_eq!(ui.layers[0].sound, NUM_SOUNDS - 1);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        assert_eq!(ui.layers[0].sound, 0);
+
+        // The volume stops at its maximum instead
+        for _ in 0..MAX_VOLUME {
+            turn_cw(&mut ui, &mut inputs, &mut pins, 1);
+        }
+        assert_eq!(ui.layers[0].volume, MAX_VOLUME);
+    }
+
+    #[test]
+    fn main_page_edits_sequence() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        assert_eq!(ui.page().params(), [Some(Param::Shift), Some(Param::Tempo), Some(Param::Length), Some(Param::Hits)]);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 1);
+        turn_ccw(&mut ui, &mut inputs, &mut pins, 2);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 3);
+
+        let layer = &ui.layers[0];
+        assert_eq!((layer.shift, layer.length, layer.hits), (1, 15, 2));
+        assert_eq!(ui.tempo, DEFAULT_TEMPO + 1);
+    }
+
+    #[test]
+    fn turn_while_held_cancels_click() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[2] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Envelope);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        pins.switches[2] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.layers[0].attack_ms, ATTACKS_MS[1]);
+        assert_eq!(ui.active_layer, 0);
+        assert_eq!(ui.page(), Page::Main);
+    }
+
+    #[test]
+    fn click_switches_layer() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[2] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        pins.switches[2] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+        assert_eq!(ui.active_layer, 1);
+    }
+
+    #[test]
+    fn double_click_routes_outputs() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        for _ in 0..2 {
+            pins.switches[3] = true;
+            poll(&mut ui, &mut inputs, &pins, 50);
+            pins.switches[3] = false;
+            poll(&mut ui, &mut inputs, &pins, 50);
+        }
+        assert_eq!(ui.routing(), Routing::Independent([Output::Both; 3]));
+
+        // Pan turns select the output of the layer
+        pins.switches[0] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        assert_eq!(ui.routing(), Routing::Independent([Output::Out2, Output::Both, Output::Both]));
+        assert_eq!(ui.layers[0].pan, 7);
+    }
+
+    #[test]
+    fn holding_d_keeps_routing() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[3] = true;
+        poll(&mut ui, &mut inputs, &pins, 600);
+        assert_eq!(ui.page(), Page::Voice);
+        pins.switches[3] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+        assert_eq!(ui.routing(), Routing::Stereo);
+    }
+
+    #[test]
+    fn shift_turn_selects_sound() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[0] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        assert_eq!(ui.page(), Page::Shift);
+        pins.switches[0] = false;
+        poll(&mut ui, &mut inputs, &pins, 50);
+
+        assert_eq!(ui.layers[0].sound, 1);
+        assert_eq!(ui.layers[0].shift, 0);
+        assert_eq!(ui.page(), Page::Main);
+
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        assert_eq!(ui.layers[0].sound, 1);
+        assert_eq!(ui.layers[0].shift, 1);
+    }
+
+    #[test]
+    fn hold_play_edits_filter() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[1] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Filter);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        pins.switches[1] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.layers[0].filter, FilterMode::Lowpass);
+        assert_eq!(ui.layers[0].resonance, 1);
+        // Turning cancels the click that pauses
+        assert!(ui.is_playing);
+    }
+
+    #[test]
+    fn long_press_restarts_on_release() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        let mut events = Vec::new();
+        pins.switches[1] = true;
+        for _ in 0..600 {
+            events.extend(ui.update(inputs.update(&pins)));
+        }
+        assert!(events.is_empty());
+        pins.switches[1] = false;
+        for _ in 0..50 {
+            events.extend(ui.update(inputs.update(&pins)));
+        }
+        assert_eq!(events.len(), 1);
+        assert!(matches!(events[0], OutputEvent::IsPlaying(true)));
+    }
+
+    #[test]
+    fn hold_play_with_turn_keeps_playing() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        let mut events = Vec::new();
+        pins.switches[1] = true;
+        for _ in 0..600 {
+            events.extend(ui.update(inputs.update(&pins)));
+        }
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        pins.switches[1] = false;
+        for _ in 0..50 {
+            events.extend(ui.update(inputs.update(&pins)));
+        }
+        assert!(!events.iter().any(|event| matches!(event, OutputEvent::IsPlaying(_))));
+        assert!(ui.is_playing);
+        assert_eq!(ui.layers[0].resonance, 1);
+    }
+
+    #[test]
+    fn hold_two_switches_edits_delay() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[0] = true;
+        pins.switches[1] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Delay);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 1);
+        pins.switches[0] = false;
+        pins.switches[1] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.layers[0].delay_send, 1);
+        assert_eq!(ui.delay.division, Division::DottedEighth);
+        assert!(ui.is_playing);
+        assert_eq!(ui.page(), Page::Main);
+    }
+
+    #[test]
+    fn hold_a_and_c_edits_reverb() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[0] = true;
+        pins.switches[2] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Reverb);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 1);
+        pins.switches[0] = false;
+        pins.switches[2] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.layers[0].reverb_send, 1);
+        assert_eq!(ui.reverb.decay, 9);
+        // The chord doesn't click to the next layer
+        assert_eq!(ui.active_layer, 0);
+    }
+
+    #[test]
+    fn hold_b_and_c_edits_sidechain() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[1] = true;
+        pins.switches[2] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Sidechain);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        pins.switches[1] = false;
+        pins.switches[2] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.sidechain, SidechainState { source: Some(0), depth: 8, release_ms: 200 });
+        assert!(ui.is_playing);
+    }
+
+    #[test]
+    fn hold_c_and_d_edits_master() {
+        let mut ui: UiState<3> = Default::default();
+        let mut inputs = Inputs::default();
+        let mut pins = MockInputPins::default();
+        pins.switches[2] = true;
+        pins.switches[3] = true;
+        poll(&mut ui, &mut inputs, &pins, 50);
+        assert_eq!(ui.page(), Page::Master);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 0);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 1);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 2);
+        turn_cw(&mut ui, &mut inputs, &mut pins, 3);
+        pins.switches[2] = false;
+        pins.switches[3] = false;
+        poll(&mut ui, &mut inputs, &pins, 300);
+
+        assert_eq!(ui.master, MasterState { compress: 1, crush: 1, downsample: 2, drive: 1 });
+        assert_eq!(crush_to_bits(ui.master.crush), 15);
+        // The chord neither switches layer nor routes the outputs
+        assert_eq!(ui.active_layer, 0);
+        assert_eq!(ui.routing(), Routing::Stereo);
+    }
+}
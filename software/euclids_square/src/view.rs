@@ -1,10 +1,40 @@
-use crate::ui::{UiState, LayerState, ViewState};
+use crate::ui::{UiState, LayerState, ViewState, MAX_PAN, MAX_TUNE, RETRIGGERS, ATTACKS_MS, DECAYS_MS, FILTER_MODS, DELAY_DIVISIONS, RELEASES_MS};
+use crate::ui::{cutoff_to_hz, resonance_to_amount};
+use crate::synthesizer::Output;
+use crate::synthesizer::filter::response;
 use crate::sequencer::Sequencer;
 use crate::leds::LedData;
+use crate::sequencer::VELOCITY_ACCENT;
 
 pub fn render<const NUM_LAYERS: usize, const MAX_SEQLEN: usize>(ui: &UiState<NUM_LAYERS>, sequencer: &Sequencer<NUM_LAYERS, MAX_SEQLEN>) -> LedData {
     match ui.view {
         ViewState::Sequencer => render_sequencer(ui.active_layer, sequencer),
+        ViewState::Sound => render_sound(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Volume => render_volume(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Pan => render_pan(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Retrigger => render_retrigger(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Tune => render_tune(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Reverse => render_reverse(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Trim => render_trim(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::Attack => render_choice(ui.active_layer, &ATTACKS_MS, &ui.layers[ui.active_layer].attack_ms),
+        ViewState::Decay => render_choice(ui.active_layer, &DECAYS_MS, &ui.layers[ui.active_layer].decay_ms),
+        ViewState::Filter => render_filter(ui.active_layer, &ui.layers[ui.active_layer]),
+        ViewState::FilterMod => render_choice(ui.active_layer, &FILTER_MODS, &ui.layers[ui.active_layer].filter_mod),
+        ViewState::DelaySend => render_level(ui.active_layer, ui.layers[ui.active_layer].delay_send),
+        ViewState::DelayTime => render_choice(ui.active_layer, &DELAY_DIVISIONS, &ui.delay.division),
+        ViewState::DelayFeedback => render_level(ui.active_layer, ui.delay.feedback),
+        ViewState::DelayDamping => render_level(ui.active_layer, ui.delay.damping),
+        ViewState::ReverbSend => render_level(ui.active_layer, ui.layers[ui.active_layer].reverb_send),
+        ViewState::ReverbDecay => render_level(ui.active_layer, ui.reverb.decay),
+        ViewState::ReverbDamping => render_level(ui.active_layer, ui.reverb.damping),
+        ViewState::SidechainSource => render_source(ui.sidechain.source),
+        ViewState::SidechainDepth => render_level(ui.active_layer, ui.sidechain.depth),
+        ViewState::SidechainRelease => render_choice(ui.active_layer, &RELEASES_MS, &ui.sidechain.release_ms),
+        ViewState::MasterCompress => render_level(ui.active_layer, ui.master.compress),
+        ViewState::MasterCrush => render_level(ui.active_layer, ui.master.crush),
+        ViewState::MasterDownsample => render_level(ui.active_layer, ui.master.downsample),
+        ViewState::MasterDrive => render_level(ui.active_layer, ui.master.drive),
+        ViewState::Output => render_output(ui.active_layer, &ui.layers[ui.active_layer], ui.independent_outputs),
         _ => render_player(sequencer),
     }
 }
@@ -17,13 +47,13 @@ fn render_player<const NUM_LAYERS: usize, const MAX_SEQLEN: usize>(sequencer: &S
     let mut led_data = [0; 16];
     for (i, seq) in sequencer.sequences.iter().enumerate() {
         for (t, &v) in seq.iter().enumerate() {
-            if v == 1 {
+            if v > 0 {
                 led_data[t] |= layer_color(i, 0x40);
             }
         }
     }
     for (i, (&step, seq)) in sequencer.steps.iter().zip(&sequencer.sequences).enumerate() {
-        if seq[step] == 1 {
+        if seq[step] > 0 {
             led_data[step] |= layer_color(i, 0xFF)
         }
     }
@@ -34,12 +64,162 @@ fn render_sequencer<const NUM_LAYERS: usize, const MAX_SEQLEN: usize>(active_lay
     let mut led_data = [0; 16];
     let seq = &sequencer.sequences[active_layer];
     for (t, &v) in seq.iter().enumerate() {
-        if v == 1 {
+        if v >= VELOCITY_ACCENT {
             led_data[t] |= layer_color(active_layer, 0xFF);
+        } else if v > 0 {
+            led_data[t] |= layer_color(active_layer, 0x80);
         } else {
             led_data[t] |= layer_color(active_layer, 0x10);
 
         }
     }
     led_data
-}
\ No newline at end of file
+}
+
+fn render_sound(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    for (t, led) in led_data.iter_mut().enumerate() {
+        *led = if t == layer.sound {
+            layer_color(active_layer, 0xFF)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
+
+fn render_volume(active_layer: usize, layer: &LayerState) -> LedData {
+    render_level(active_layer, layer.volume)
+}
+
+// A led per step of the level
+fn render_level(active_layer: usize, level: usize) -> LedData {
+    let mut led_data = [0; 16];
+    for (t, led) in led_data.iter_mut().enumerate() {
+        if t < level {
+            *led = layer_color(active_layer, 0xFF);
+        }
+    }
+    led_data
+}
+
+// All leds in the colour of the source layer, dark when off
+fn render_source(source: Option<usize>) -> LedData {
+    match source {
+        Some(source) => [layer_color(source, 0xFF); 16],
+        None => [0; 16],
+    }
+}
+
+// A bar from the center to the pan position, on the first 15 leds
+fn render_pan(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    let center = MAX_PAN / 2;
+    let (from, to) = if layer.pan < center { (layer.pan, center) } else { (center, layer.pan) };
+    for (t, led) in led_data.iter_mut().enumerate().take(MAX_PAN + 1) {
+        *led = if t == layer.pan {
+            layer_color(active_layer, 0xFF)
+        } else if from <= t && t <= to {
+            layer_color(active_layer, 0x80)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
+
+// The left half lights for DAC_OUT1 and the right half for DAC_OUT2, dimmed in stereo mode
+fn render_output(active_layer: usize, layer: &LayerState, independent_outputs: bool) -> LedData {
+    let mut led_data = [0; 16];
+    let val = if independent_outputs { 0xFF } else { 0x10 };
+    for (t, led) in led_data.iter_mut().enumerate() {
+        let is_on = match layer.output {
+            Output::Out1 => t < 8,
+            Output::Out2 => t >= 8,
+            Output::Both => true,
+        };
+        if is_on {
+            *led = layer_color(active_layer, val);
+        }
+    }
+    led_data
+}
+
+fn render_retrigger(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    for (led, &retrigger) in led_data.iter_mut().zip(RETRIGGERS.iter()) {
+        *led = if retrigger == layer.retrigger {
+            layer_color(active_layer, 0xFF)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
+
+// The number of semitones, bright when tuned up and dim when tuned down
+fn render_tune(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    let val = if layer.tune > 0 { 0xFF } else { 0x40 };
+    for (t, led) in led_data.iter_mut().enumerate().take(MAX_TUNE as usize) {
+        *led = if (t as isize) < layer.tune.abs() {
+            layer_color(active_layer, val)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
+
+// Brightness increasing in playback direction
+fn render_reverse(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    for (t, led) in led_data.iter_mut().enumerate() {
+        let t = if layer.reverse { 15 - t } else { t };
+        *led = layer_color(active_layer, 0x10 + 0x0F * t as u8);
+    }
+    led_data
+}
+
+// The part of the sound that plays, a led per sixteenth
+fn render_trim(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    for (t, led) in led_data.iter_mut().enumerate() {
+        *led = if layer.start <= t && t < layer.end {
+            layer_color(active_layer, 0xFF)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
+
+// The frequency response, a led per cutoff step with the cutoff at full brightness
+fn render_filter(active_layer: usize, layer: &LayerState) -> LedData {
+    let mut led_data = [0; 16];
+    let cutoff = cutoff_to_hz(layer.cutoff);
+    let resonance = resonance_to_amount(layer.resonance);
+    for (t, led) in led_data.iter_mut().enumerate() {
+        let gain = response(layer.filter, cutoff, resonance, cutoff_to_hz(t)).min(1.);
+        *led = if t == layer.cutoff {
+            layer_color(active_layer, 0xFF)
+        } else {
+            layer_color(active_layer, 0x10 + (0xA0 as f32 * gain) as u8)
+        };
+    }
+    led_data
+}
+
+// A bar up to the selected value of the choices
+fn render_choice<T: PartialEq>(active_layer: usize, choices: &[T], value: &T) -> LedData {
+    let mut led_data = [0; 16];
+    let selected = choices.iter().position(|choice| choice == value).unwrap_or(0);
+    for (t, led) in led_data.iter_mut().enumerate().take(choices.len()) {
+        *led = if t <= selected {
+            layer_color(active_layer, 0xFF)
+        } else {
+            layer_color(active_layer, 0x10)
+        };
+    }
+    led_data
+}
@@ -0,0 +1,41 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+use euclids_square::midi::{write_smf, SmfFormat};
+use euclids_square::ui::tempo_to_bpm;
+use euclids_square_sim::cli::{parse_pattern, PATTERN_OPTIONS};
+
+fn parse_args(args: &[String]) -> Option<(euclids_square_sim::render::Pattern, SmfFormat, String)> {
+    let (pattern, rest) = parse_pattern(args)?;
+    let mut format = SmfFormat::MultiTrack;
+    let mut output = None;
+    let mut rest = rest.iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--type" => format = match rest.next()?.as_str() {
+                "0" => SmfFormat::SingleTrack,
+                "1" => SmfFormat::MultiTrack,
+                _ => return None,
+            },
+            _ if output.is_none() && !arg.starts_with("--") => output = Some(arg.clone()),
+            _ => return None,
+        }
+    }
+    Some((pattern, format, output?))
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (pattern, format, output) = parse_args(&args).unwrap_or_else(|| {
+        eprintln!(
+            "Exports a pattern as Standard MIDI File\n\nUsage: midi_export [OPTIONS] OUTPUT.mid\n\nOptions:\n{}\n    --type TYPE       MIDI file type, 0 or 1 [default: 1]",
+            PATTERN_OPTIONS,
+        );
+        process::exit(1);
+    });
+
+    let mut bytes = Vec::new();
+    write_smf(&pattern.sequencer(), &pattern.layers, tempo_to_bpm(pattern.tempo), pattern.bars, format, |b| bytes.extend_from_slice(b));
+    fs::write(output, bytes)
+}
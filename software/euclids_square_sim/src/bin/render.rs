@@ -0,0 +1,23 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use euclids_square::synthesizer::SAMPLE_FREQ;
+use euclids_square_sim::cli::{parse_pattern, PATTERN_OPTIONS};
+use euclids_square_sim::render::render;
+use euclids_square_sim::wav::WavWriter;
+
+fn main() -> io::Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (pattern, output) = match parse_pattern(&args) {
+        Some((pattern, rest)) if rest.len() == 1 && !rest[0].starts_with("--") => (pattern, rest[0].clone()),
+        _ => {
+            eprintln!("Renders a pattern as the instrument would play it\n\nUsage: render [OPTIONS] OUTPUT.wav\n\nOptions:\n{}", PATTERN_OPTIONS);
+            process::exit(1);
+        },
+    };
+
+    let mut wav = WavWriter::new(BufWriter::new(File::create(output)?), SAMPLE_FREQ, 2)?;
+    wav.write_samples(&render(&pattern))?;
+    wav.finish()?.flush()
+}
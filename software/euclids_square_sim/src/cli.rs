@@ -0,0 +1,112 @@
+use euclids_square::synthesizer::NUM_SOUNDS;
+use euclids_square::ui::{LayerState, DelayState, ReverbState, SidechainState, MasterState, MAX_PAN, MAX_VOLUME, MAX_SEND, MAX_FEEDBACK, MAX_DAMPING, MAX_DECAY, MAX_DEPTH, DELAY_DIVISIONS, RELEASES_MS};
+use euclids_square::ui::{MAX_COMPRESS, MAX_CRUSH, MAX_DOWNSAMPLE, MAX_DRIVE};
+use crate::instrument::NUM_LAYERS;
+use crate::render::Pattern;
+
+pub const PATTERN_OPTIONS: &str = "\
+    --layer LENGTH,HITS,SHIFT,SOUND,VOLUME[,ACCENTS[,PAN[,DELAY[,REVERB]]]]
+                      Settings of the next layer, layers without settings are silent
+    --tempo TEMPO     Tempo setting from 1 to 16 [default: 8]
+    --bars BARS       Number of bars of 16 steps [default: 1]
+    --delay TIME,FEEDBACK,DAMPING
+                      Delay of the layer sends, TIME from 0 for a sixteenth to 5 for a dotted quarter
+    --reverb DECAY,DAMPING
+                      Reverb of the layer sends
+    --sidechain SOURCE,DEPTH,RELEASE
+                      Duck the other layers when layer SOURCE from 0 hits, RELEASE from 0 for 50 ms to 7 for 600 ms
+    --master COMPRESS,CRUSH,DOWNSAMPLE,DRIVE
+                      Master bus as on the instrument, COMPRESS from 0 for bypassed to 8 for -24 dB, CRUSH
+                      the bits taken off, DOWNSAMPLE from 1 to 16 frames and DRIVE from 0 for bypassed to 8";
+
+fn parse_layer(arg: &str) -> Option<LayerState> {
+    let values: Vec<isize> = arg.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    let (length, hits, shift, sound, volume) = match values[..] {
+        [length, hits, shift, sound, volume, ..] if values.len() <= 9 => (length, hits, shift, sound, volume),
+        _ => return None,
+    };
+    let layer = LayerState {
+        sound: sound as usize,
+        volume: volume as usize,
+        length: length as usize,
+        hits: hits as usize,
+        shift,
+        accents: values.get(5).map_or(0, |&accents| accents as usize),
+        pan: values.get(6).map_or(LayerState::default().pan, |&pan| pan as usize),
+        delay_send: values.get(7).map_or(0, |&send| send as usize),
+        reverb_send: values.get(8).map_or(0, |&send| send as usize),
+        ..Default::default()
+    };
+    let is_valid = (1..=16).contains(&layer.length)
+        && layer.hits <= layer.length
+        && layer.accents <= layer.hits
+        && layer.sound < NUM_SOUNDS
+        && layer.volume <= MAX_VOLUME
+        && layer.pan <= MAX_PAN
+        && layer.delay_send <= MAX_SEND
+        && layer.reverb_send <= MAX_SEND;
+    if is_valid { Some(layer) } else { None }
+}
+
+fn parse_delay(arg: &str) -> Option<DelayState> {
+    let values: Vec<usize> = arg.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    match values[..] {
+        [time, feedback, damping] if time < DELAY_DIVISIONS.len() && feedback <= MAX_FEEDBACK && damping <= MAX_DAMPING => {
+            Some(DelayState { division: DELAY_DIVISIONS[time], feedback, damping })
+        },
+        _ => None,
+    }
+}
+
+fn parse_reverb(arg: &str) -> Option<ReverbState> {
+    let values: Vec<usize> = arg.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    match values[..] {
+        [decay, damping] if decay <= MAX_DECAY && damping <= MAX_DAMPING => Some(ReverbState { decay, damping }),
+        _ => None,
+    }
+}
+
+fn parse_sidechain(arg: &str) -> Option<SidechainState> {
+    let values: Vec<usize> = arg.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    match values[..] {
+        [source, depth, release] if source < NUM_LAYERS && depth <= MAX_DEPTH && release < RELEASES_MS.len() => {
+            Some(SidechainState { source: Some(source), depth, release_ms: RELEASES_MS[release] })
+        },
+        _ => None,
+    }
+}
+
+fn parse_master(arg: &str) -> Option<MasterState> {
+    let values: Vec<usize> = arg.split(',').map(|v| v.trim().parse().ok()).collect::<Option<_>>()?;
+    match values[..] {
+        [compress, crush, downsample, drive] if compress <= MAX_COMPRESS && crush <= MAX_CRUSH && (1..=MAX_DOWNSAMPLE).contains(&downsample) && drive <= MAX_DRIVE => {
+            Some(MasterState { compress, crush, downsample, drive })
+        },
+        _ => None,
+    }
+}
+
+// Parses the pattern options, returning the other arguments
+pub fn parse_pattern(args: &[String]) -> Option<(Pattern, Vec<String>)> {
+    let mut pattern = Pattern::default();
+    let mut num_layers = 0;
+    let mut rest = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--layer" if num_layers < NUM_LAYERS => {
+                pattern.layers[num_layers] = parse_layer(args.next()?)?;
+                num_layers += 1;
+            },
+            "--tempo" => pattern.tempo = args.next()?.parse().ok().filter(|tempo| (1..=16).contains(tempo))?,
+            "--bars" => pattern.bars = args.next()?.parse().ok()?,
+            "--delay" => pattern.delay = parse_delay(args.next()?)?,
+            "--reverb" => pattern.reverb = parse_reverb(args.next()?)?,
+            "--sidechain" => pattern.sidechain = parse_sidechain(args.next()?)?,
+            "--master" => pattern.master = parse_master(args.next()?)?,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    Some((pattern, rest))
+}
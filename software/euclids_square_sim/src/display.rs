@@ -0,0 +1,53 @@
+use std::f32::consts::PI;
+use std::io::{self, Write};
+use crossterm::{cursor, queue, style, terminal};
+use euclids_square::leds::LedData;
+use euclids_square_sim::instrument::{Instrument, NUM_LAYERS};
+use euclids_square::ui::tempo_to_bpm;
+
+const CENTER: (f32, f32) = (18., 8.);
+const RADIUS: (f32, f32) = (16., 7.);
+
+pub fn draw<W: Write>(out: &mut W, instrument: &Instrument, latched: &[bool; 4]) -> io::Result<()> {
+    queue!(out, terminal::Clear(terminal::ClearType::All))?;
+    draw_ring(out, &instrument.led_data)?;
+
+    let ui = &instrument.ui;
+    let layer = &ui.layers[ui.active_layer];
+    let held: String = latched.iter()
+        .zip("ABCD".chars())
+        .filter(|(&latched, _)| latched)
+        .map(|(_, name)| name)
+        .collect();
+    let lines = [
+        format!("{} {} BPM   layer {}/{}   page {:?}   view {:?}", if ui.is_playing { "playing" } else { "paused" }, tempo_to_bpm(ui.tempo), ui.active_layer + 1, NUM_LAYERS, ui.page(), ui.view),
+        format!("{:?}", layer),
+        format!("held switches: {}", held),
+        String::from("encoders q/a w/s e/d r/f, click 1-4, hold !@#$, quit Esc"),
+    ];
+    for (i, line) in lines.iter().enumerate() {
+        queue!(out, cursor::MoveTo(0, 2 * CENTER.1 as u16 + 2 + i as u16), style::Print(line))?;
+    }
+    out.flush()
+}
+
+fn draw_ring<W: Write>(out: &mut W, led_data: &LedData) -> io::Result<()> {
+    for (i, &color) in led_data.iter().enumerate() {
+        let angle = i as f32 / led_data.len() as f32 * 2. * PI;
+        let x = CENTER.0 + RADIUS.0 * angle.sin();
+        let y = CENTER.1 - RADIUS.1 * angle.cos();
+        let (symbol, color) = if color == 0 {
+            ("○", style::Color::Rgb { r: 0x40, g: 0x40, b: 0x40 })
+        } else {
+            ("●", style::Color::Rgb { r: (color >> 16) as u8, g: (color >> 8) as u8, b: color as u8 })
+        };
+        queue!(
+            out,
+            cursor::MoveTo(x.round() as u16, y.round() as u16),
+            style::SetForegroundColor(color),
+            style::Print(symbol),
+            style::ResetColor,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,201 @@
+use euclids_square::hal::InputPins;
+use euclids_square::hal::mock::MockAudioSink;
+use euclids_square::inputs::Inputs;
+use euclids_square::leds::LedData;
+use euclids_square::midi::{write_smf, SmfFormat};
+use euclids_square::sequencer::{Sequencer, step_period_us};
+use euclids_square::synthesizer::{BUFFER_LEN, SAMPLE_FREQ, dma_handler, DmaState, Synth, SynthVoice};
+use euclids_square::synthesizer::delay::{Delay, DELAY_LEN};
+use euclids_square::synthesizer::reverb::{Reverb, REVERB_LEN};
+use euclids_square::ui::{UiState, OutputEvent, volume_to_gain, pan_to_position, trim_to_fraction, tempo_to_bpm, cutoff_to_hz, resonance_to_amount, DEFAULT_TEMPO};
+use euclids_square::ui::{send_to_gain, feedback_to_amount, damping_to_amount, decay_to_amount, depth_to_amount};
+use euclids_square::ui::{compress_to_threshold, crush_to_bits, drive_to_gain};
+use euclids_square::view::render;
+
+pub const NUM_LAYERS: usize = 3;
+
+// The firmware's tasks, with the timers replaced by a millisecond tick
+pub struct Instrument {
+    pub inputs: Inputs,
+    pub ui: UiState<NUM_LAYERS>,
+    pub sequencer: Sequencer<NUM_LAYERS, 16>,
+    pub synth: Synth<NUM_LAYERS>,
+    pub led_data: LedData,
+    // Standard MIDI File of the last dump
+    pub midi_dump: Option<Vec<u8>>,
+    audio_buffer: [u32; BUFFER_LEN],
+    audio_sink: MockAudioSink,
+    is_playing: bool,
+    step_period_us: u32,
+    time_since_step_us: u32,
+    time_ms: u64,
+    rendered_samples: u64,
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        let mut sequencer: Sequencer<NUM_LAYERS, 16> = Default::default();
+        sequencer.set_sequence(0, 16, 1, 0, 0);
+        sequencer.set_sequence(1, 16, 0, 0, 0);
+        sequencer.set_sequence(2, 16, 0, 0, 0);
+        let mut synth = Synth::new([SynthVoice::new(0), SynthVoice::new(1), SynthVoice::new(2)]);
+        // The firmware's lines are static, an instrument keeps its lines until the end
+        synth.delay = Delay::new(vec![[0; 2]; DELAY_LEN].leak());
+        synth.delay.set_tempo(tempo_to_bpm(DEFAULT_TEMPO));
+        synth.reverb = Reverb::new(vec![0; REVERB_LEN].leak());
+        let mut instrument = Self {
+            inputs: Default::default(),
+            ui: Default::default(),
+            sequencer,
+            synth,
+            led_data: [0; 16],
+            midi_dump: None,
+            audio_buffer: [0; BUFFER_LEN],
+            audio_sink: Default::default(),
+            is_playing: true,
+            step_period_us: timer_period_us(DEFAULT_TEMPO),
+            time_since_step_us: 0,
+            time_ms: 0,
+            rendered_samples: 0,
+        };
+        // The effects start as the UI shows them
+        instrument.apply(OutputEvent::DelayUpdate(instrument.ui.delay));
+        instrument.apply(OutputEvent::ReverbUpdate(instrument.ui.reverb));
+        instrument.apply(OutputEvent::SidechainUpdate(instrument.ui.sidechain));
+        instrument.apply(OutputEvent::MasterUpdate(instrument.ui.master));
+        instrument
+    }
+}
+
+// The sequencer timer counts in steps of 10 us
+fn timer_period_us(tempo: usize) -> u32 {
+    step_period_us(tempo_to_bpm(tempo)) / 10 * 10
+}
+
+// Converts a DAC word to stereo 16 bit samples
+fn dac_to_samples(word: u32) -> [i16; 2] {
+    let to_i16 = |v: u32| (((v & 0xFFF) as i32 - 2048) << 4) as i16;
+    [to_i16(word), to_i16(word >> 16)]
+}
+
+impl Instrument {
+    // Advances 1 ms: polls the inputs, steps the sequencer and appends the audio
+    // of the DMA transfers that completed as interleaved stereo samples.
+    pub fn tick_ms<P: InputPins>(&mut self, pins: &P, audio: &mut Vec<i16>) {
+        let input_event = self.inputs.update(pins);
+        for output_event in self.ui.update(input_event) {
+            self.apply(output_event);
+        }
+        self.led_data = render(&self.ui, &self.sequencer);
+
+        if self.is_playing {
+            self.time_since_step_us += 1000;
+            while self.time_since_step_us >= self.step_period_us {
+                self.time_since_step_us -= self.step_period_us;
+                let velocities = self.sequencer.step();
+                self.synth.apply_gates(velocities);
+            }
+        }
+
+        self.time_ms += 1;
+        let half = BUFFER_LEN as u64 / 2;
+        while self.rendered_samples + half <= self.time_ms * SAMPLE_FREQ as u64 / 1000 {
+            let mid = BUFFER_LEN / 2;
+            let half_buffer = match dma_handler(&mut self.audio_sink, &mut self.audio_buffer, &mut self.synth) {
+                DmaState::Ht => &self.audio_buffer[..mid],
+                _ => &self.audio_buffer[mid..],
+            };
+            for &word in half_buffer {
+                audio.extend_from_slice(&dac_to_samples(word));
+            }
+            self.rendered_samples += half;
+        }
+    }
+
+    // Starts from the first step on the next tick, like an update event of the sequencer timer
+    pub fn restart(&mut self) {
+        self.sequencer.reset_steps();
+        self.time_since_step_us = self.step_period_us - 1000;
+    }
+
+    pub fn step_period_us(&self) -> u32 {
+        self.step_period_us
+    }
+
+    pub fn apply(&mut self, output_event: OutputEvent) {
+        match output_event {
+            OutputEvent::LayerUpdate (layer, layer_state) => {
+                self.sequencer.set_sequence(layer, layer_state.length, layer_state.hits, layer_state.shift, layer_state.accents);
+            },
+            OutputEvent::SoundUpdate (layer, sound) => {
+                self.synth.voices[layer].set_sound(sound);
+            },
+            OutputEvent::VolumeUpdate (layer, volume) => {
+                self.synth.voices[layer].set_volume(volume_to_gain(volume));
+            },
+            OutputEvent::PanUpdate (layer, pan) => {
+                self.synth.voices[layer].set_pan(pan_to_position(pan));
+            },
+            OutputEvent::RetriggerUpdate (layer, retrigger) => {
+                self.synth.voices[layer].set_retrigger(retrigger);
+            },
+            OutputEvent::TuneUpdate (layer, tune) => {
+                self.synth.voices[layer].set_tune(tune as f32);
+            },
+            OutputEvent::ReverseUpdate (layer, reverse) => {
+                self.synth.voices[layer].set_reverse(reverse);
+            },
+            OutputEvent::TrimUpdate (layer, start, end) => {
+                self.synth.voices[layer].set_trim(trim_to_fraction(start), trim_to_fraction(end));
+            },
+            OutputEvent::EnvelopeUpdate (layer, attack_ms, decay_ms) => {
+                self.synth.voices[layer].set_envelope(attack_ms, decay_ms);
+            },
+            OutputEvent::FilterUpdate (layer, mode, cutoff, resonance, modulation) => {
+                self.synth.voices[layer].set_filter(mode, cutoff_to_hz(cutoff), resonance_to_amount(resonance), modulation);
+            },
+            OutputEvent::SendUpdate (layer, effect, send) => {
+                self.synth.voices[layer].set_send(effect, send_to_gain(send));
+            },
+            OutputEvent::DelayUpdate (delay) => {
+                self.synth.delay.set_division(delay.division);
+                self.synth.delay.feedback = feedback_to_amount(delay.feedback);
+                self.synth.delay.damping = damping_to_amount(delay.damping);
+            },
+            OutputEvent::ReverbUpdate (reverb) => {
+                self.synth.reverb.decay = decay_to_amount(reverb.decay);
+                self.synth.reverb.damping = damping_to_amount(reverb.damping);
+            },
+            OutputEvent::SidechainUpdate (sidechain) => {
+                self.synth.sidechain.source = sidechain.source;
+                self.synth.sidechain.depth = depth_to_amount(sidechain.depth);
+                self.synth.sidechain.release_ms = sidechain.release_ms as f32;
+            },
+            OutputEvent::MasterUpdate (master) => {
+                self.synth.master.compressor.bypass = master.compress == 0;
+                self.synth.master.compressor.threshold = compress_to_threshold(master.compress);
+                self.synth.master.bitcrusher.bypass = master.crush == 0 && master.downsample == 1;
+                self.synth.master.bitcrusher.set_bits(crush_to_bits(master.crush));
+                self.synth.master.bitcrusher.downsample = master.downsample as u32;
+                self.synth.master.soft_clip.bypass = master.drive == 0;
+                self.synth.master.soft_clip.drive = drive_to_gain(master.drive);
+            },
+            OutputEvent::RoutingUpdate => {
+                self.synth.set_routing(self.ui.routing());
+            },
+            OutputEvent::TempoUpdate (tempo) => {
+                self.step_period_us = timer_period_us(tempo);
+                self.synth.delay.set_tempo(tempo_to_bpm(tempo));
+            },
+            OutputEvent::IsPlaying (is_playing) => {
+                self.is_playing = is_playing;
+                self.sequencer.reset_steps();
+            },
+            OutputEvent::DumpMidi => {
+                let mut bytes = Vec::new();
+                write_smf(&self.sequencer, &self.ui.layers, tempo_to_bpm(self.ui.tempo), 1, SmfFormat::MultiTrack, |b| bytes.extend_from_slice(b));
+                self.midi_dump = Some(bytes);
+            },
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub mod cli;
+pub mod instrument;
+pub mod render;
+pub mod wav;
@@ -0,0 +1,118 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::{cursor, execute, terminal};
+use euclids_square::synthesizer::SAMPLE_FREQ;
+use euclids_square_sim::instrument::Instrument;
+use euclids_square_sim::wav::WavWriter;
+
+mod display;
+mod panel;
+
+use panel::Panel;
+
+const DRAW_PERIOD_MS: u64 = 30;
+const MIDI_DUMP_PATH: &str = "pattern.mid";
+
+enum AudioOut {
+    Wav(WavWriter<BufWriter<File>>),
+    Raw(io::Stdout),
+}
+
+impl AudioOut {
+    fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        match self {
+            AudioOut::Wav(wav) => wav.write_samples(samples),
+            AudioOut::Raw(stdout) => {
+                let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect();
+                stdout.write_all(&bytes)
+            },
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            AudioOut::Wav(wav) => wav.finish()?.flush(),
+            AudioOut::Raw(mut stdout) => stdout.flush(),
+        }
+    }
+}
+
+// Returns false when quitting
+fn handle_key(key: KeyEvent, panel: &mut Panel) -> bool {
+    match key.code {
+        KeyCode::Esc => return false,
+        KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return false,
+        KeyCode::Char(c) => {
+            if let Some(i) = "qwer".find(c) {
+                panel.turn(i, true);
+            } else if let Some(i) = "asdf".find(c) {
+                panel.turn(i, false);
+            } else if let Some(i) = "1234".find(c) {
+                panel.click(i);
+            } else if let Some(i) = "!@#$".find(c) {
+                panel.toggle(i);
+            }
+        },
+        _ => (),
+    }
+    true
+}
+
+fn run(audio_out: &mut AudioOut) -> io::Result<()> {
+    let mut stderr = io::stderr();
+    let mut instrument = Instrument::default();
+    let mut panel = Panel::default();
+    let mut audio = Vec::new();
+    let start = Instant::now();
+
+    for time_ms in 0.. {
+        while event::poll(Duration::from_secs(0))? {
+            if let Event::Key(key) = event::read()? {
+                if !handle_key(key, &mut panel) {
+                    return Ok(());
+                }
+            }
+        }
+
+        panel.tick_ms();
+        instrument.tick_ms(&panel.pins, &mut audio);
+        if let Some(bytes) = instrument.midi_dump.take() {
+            fs::write(MIDI_DUMP_PATH, bytes)?;
+        }
+        audio_out.write_samples(&audio)?;
+        audio.clear();
+
+        if time_ms % DRAW_PERIOD_MS == 0 {
+            display::draw(&mut stderr, &instrument, &panel.latched)?;
+        }
+
+        let next_tick = start + Duration::from_millis(time_ms + 1);
+        if let Some(wait) = next_tick.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+    Ok(())
+}
+
+fn main() -> io::Result<()> {
+    let output = env::args().nth(1).unwrap_or_else(|| String::from("out.wav"));
+    let mut audio_out = if output == "-" {
+        AudioOut::Raw(io::stdout())
+    } else {
+        AudioOut::Wav(WavWriter::new(BufWriter::new(File::create(&output)?), SAMPLE_FREQ, 2)?)
+    };
+
+    let mut stderr = io::stderr();
+    terminal::enable_raw_mode()?;
+    execute!(stderr, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let result = run(&mut audio_out);
+    execute!(stderr, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+
+    result?;
+    audio_out.finish()
+}
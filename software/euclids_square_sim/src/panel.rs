@@ -0,0 +1,50 @@
+use std::collections::VecDeque;
+use euclids_square::hal::mock::MockInputPins;
+
+// Clockwise quadrature sequence of (pin A, pin B) for one detent
+const CW: [(bool, bool); 4] = [(true, false), (true, true), (false, true), (false, false)];
+const CCW: [(bool, bool); 4] = [(false, true), (true, true), (true, false), (false, false)];
+
+// Time each quadrature state and a click are held, long enough to pass the debouncers
+const STATE_MS: u16 = 3;
+const CLICK_MS: u16 = 60;
+
+// Turns key presses into the pin levels of the front panel
+#[derive(Default)]
+pub struct Panel {
+    pub pins: MockInputPins,
+    pub latched: [bool; 4],
+    clicks: [u16; 4],
+    encoder_states: [VecDeque<(bool, bool)>; 4],
+    state_ms: [u16; 4],
+}
+
+impl Panel {
+    pub fn turn(&mut self, encoder: usize, cw: bool) {
+        let states = if cw { &CW } else { &CCW };
+        self.encoder_states[encoder].extend(states.iter());
+    }
+
+    pub fn click(&mut self, switch: usize) {
+        self.clicks[switch] = CLICK_MS;
+    }
+
+    pub fn toggle(&mut self, switch: usize) {
+        self.latched[switch] = !self.latched[switch];
+    }
+
+    pub fn tick_ms(&mut self) {
+        for i in 0..4 {
+            self.pins.switches[i] = self.latched[i] || self.clicks[i] > 0;
+            self.clicks[i] = self.clicks[i].saturating_sub(1);
+
+            if self.state_ms[i] == 0 {
+                if let Some(state) = self.encoder_states[i].pop_front() {
+                    self.pins.encoders[i] = state;
+                    self.state_ms[i] = STATE_MS;
+                }
+            }
+            self.state_ms[i] = self.state_ms[i].saturating_sub(1);
+        }
+    }
+}
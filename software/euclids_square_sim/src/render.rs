@@ -0,0 +1,78 @@
+use euclids_square::hal::mock::MockInputPins;
+use euclids_square::sequencer::Sequencer;
+use euclids_square::synthesizer::SAMPLE_FREQ;
+use euclids_square::synthesizer::Effect;
+use euclids_square::ui::{UiState, LayerState, DelayState, ReverbState, SidechainState, MasterState, OutputEvent, DEFAULT_TEMPO};
+use crate::instrument::{Instrument, NUM_LAYERS};
+
+pub struct Pattern {
+    pub layers: [LayerState; NUM_LAYERS],
+    pub tempo: usize,
+    pub bars: usize,
+    pub delay: DelayState,
+    pub reverb: ReverbState,
+    pub sidechain: SidechainState,
+    pub master: MasterState,
+}
+
+// One bar of silence
+impl Default for Pattern {
+    fn default() -> Self {
+        let mut layers = UiState::<NUM_LAYERS>::default().layers;
+        for layer in layers.iter_mut() {
+            layer.hits = 0;
+        }
+        Self { layers, tempo: DEFAULT_TEMPO, bars: 1, delay: DelayState::default(), reverb: ReverbState::default(), sidechain: SidechainState::default(), master: MasterState::default() }
+    }
+}
+
+impl Pattern {
+    pub fn sequencer(&self) -> Sequencer<NUM_LAYERS, 16> {
+        let mut sequencer: Sequencer<NUM_LAYERS, 16> = Default::default();
+        for (i, layer) in self.layers.iter().enumerate() {
+            sequencer.set_sequence(i, layer.length, layer.hits, layer.shift, layer.accents);
+        }
+        sequencer
+    }
+}
+
+// Renders the pattern from its first step as interleaved stereo samples
+pub fn render(pattern: &Pattern) -> Vec<i16> {
+    let mut instrument = Instrument::default();
+    for (i, layer) in pattern.layers.iter().enumerate() {
+        instrument.ui.layers[i] = layer.clone();
+        instrument.apply(OutputEvent::LayerUpdate(i, layer.clone()));
+        instrument.apply(OutputEvent::SoundUpdate(i, layer.sound));
+        instrument.apply(OutputEvent::VolumeUpdate(i, layer.volume));
+        instrument.apply(OutputEvent::PanUpdate(i, layer.pan));
+        instrument.apply(OutputEvent::RetriggerUpdate(i, layer.retrigger));
+        instrument.apply(OutputEvent::TuneUpdate(i, layer.tune));
+        instrument.apply(OutputEvent::ReverseUpdate(i, layer.reverse));
+        instrument.apply(OutputEvent::TrimUpdate(i, layer.start, layer.end));
+        instrument.apply(OutputEvent::EnvelopeUpdate(i, layer.attack_ms, layer.decay_ms));
+        instrument.apply(OutputEvent::FilterUpdate(i, layer.filter, layer.cutoff, layer.resonance, layer.filter_mod));
+        instrument.apply(OutputEvent::SendUpdate(i, Effect::Delay, layer.delay_send));
+        instrument.apply(OutputEvent::SendUpdate(i, Effect::Reverb, layer.reverb_send));
+    }
+    instrument.ui.delay = pattern.delay;
+    instrument.apply(OutputEvent::DelayUpdate(pattern.delay));
+    instrument.ui.reverb = pattern.reverb;
+    instrument.apply(OutputEvent::ReverbUpdate(pattern.reverb));
+    instrument.ui.sidechain = pattern.sidechain;
+    instrument.apply(OutputEvent::SidechainUpdate(pattern.sidechain));
+    instrument.ui.master = pattern.master;
+    instrument.apply(OutputEvent::MasterUpdate(pattern.master));
+    instrument.ui.tempo = pattern.tempo;
+    instrument.apply(OutputEvent::TempoUpdate(pattern.tempo));
+    instrument.restart();
+
+    let duration_us = (pattern.bars * 16) as u64 * instrument.step_period_us() as u64;
+    let num_samples = 2 * (duration_us * SAMPLE_FREQ as u64 / 1_000_000) as usize;
+    let pins = MockInputPins::default();
+    let mut audio = Vec::with_capacity(num_samples);
+    while audio.len() < num_samples {
+        instrument.tick_ms(&pins, &mut audio);
+    }
+    audio.truncate(num_samples);
+    audio
+}
@@ -0,0 +1,104 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use euclids_square::ui::{LayerState, SidechainState, MasterState};
+use euclids_square_sim::render::{render, Pattern};
+
+// Samples per line of the golden files
+const BLOCK_LEN: usize = 1024;
+
+// Summarises the audio per block as a hash and the peak level, so a regression
+// shows where in the audio it starts. Set BLESS=1 to update the golden files.
+fn check_golden(name: &str, audio: &[i16]) {
+    let summary: String = audio.chunks(BLOCK_LEN)
+        .enumerate()
+        .map(|(i, block)| {
+            // FNV-1a
+            let hash = block.iter()
+                .flat_map(|sample| sample.to_le_bytes().to_vec())
+                .fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3));
+            let peak = block.iter().map(|&sample| (sample as i32).abs()).max().unwrap_or(0);
+            format!("{:5} {:016x} {:5}\n", i, hash, peak)
+        })
+        .collect();
+
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", &format!("{}.txt", name)].iter().collect();
+    if env::var_os("BLESS").is_some() {
+        fs::write(&path, &summary).unwrap();
+    }
+    let golden = fs::read_to_string(&path).expect("missing golden file, run with BLESS=1 to create it");
+    assert!(summary == golden, "audio of {} differs from {}", name, path.display());
+}
+
+fn layer(length: usize, hits: usize, shift: isize, sound: usize, volume: usize, accents: usize) -> LayerState {
+    LayerState { sound, volume, length, hits, shift, accents, ..Default::default() }
+}
+
+#[test]
+fn silence() {
+    let audio = render(&Pattern { tempo: 16, ..Default::default() });
+    assert!(audio.iter().all(|&sample| sample == audio[0]));
+}
+
+#[test]
+fn golden_single_layer() {
+    let mut pattern = Pattern { tempo: 16, ..Default::default() };
+    pattern.layers[0] = layer(8, 3, 0, 0, 8, 1);
+    check_golden("single_layer", &render(&pattern));
+}
+
+#[test]
+fn golden_three_layers() {
+    let layers = [
+        layer(16, 4, 0, 0, 10, 2),
+        LayerState { pan: 2, ..layer(16, 2, 4, 1, 8, 0) },
+        LayerState { pan: 12, ..layer(12, 7, 1, 2, 6, 3) },
+    ];
+    check_golden("three_layers", &render(&Pattern { layers, tempo: 12, ..Default::default() }));
+}
+
+#[test]
+fn golden_master_bus() {
+    let mut pattern = Pattern { tempo: 16, ..Default::default() };
+    pattern.layers[0] = layer(8, 3, 0, 0, 16, 1);
+    pattern.layers[1] = layer(16, 5, 2, 2, 12, 0);
+    pattern.master = MasterState { compress: 2, crush: 8, downsample: 3, drive: 8 };
+    check_golden("master_bus", &render(&pattern));
+}
+
+#[test]
+fn golden_delay() {
+    let mut pattern = Pattern { tempo: 10, ..Default::default() };
+    pattern.layers[0] = layer(16, 1, 0, 0, 8, 0);
+    pattern.layers[1] = LayerState { delay_send: 6, pan: 2, ..layer(8, 1, 2, 1, 8, 0) };
+    pattern.delay.feedback = 10;
+    pattern.bars = 2;
+    check_golden("delay", &render(&pattern));
+}
+
+#[test]
+fn golden_reverb() {
+    let mut pattern = Pattern { tempo: 12, ..Default::default() };
+    pattern.layers[0] = layer(16, 2, 0, 0, 8, 0);
+    pattern.layers[2] = LayerState { reverb_send: 8, ..layer(8, 3, 1, 2, 8, 1) };
+    pattern.reverb.decay = 12;
+    check_golden("reverb", &render(&pattern));
+}
+
+#[test]
+fn golden_sidechain() {
+    let mut pattern = Pattern { tempo: 12, ..Default::default() };
+    pattern.layers[0] = layer(4, 4, 0, 0, 8, 2);
+    pattern.layers[1] = layer(16, 16, 0, 3, 8, 0);
+    pattern.sidechain = SidechainState { source: Some(0), depth: 12, release_ms: 200 };
+    check_golden("sidechain", &render(&pattern));
+}
+
+#[test]
+fn hard_pan_silences_other_channel() {
+    let mut pattern = Pattern { tempo: 16, ..Default::default() };
+    pattern.layers[0] = LayerState { pan: 0, ..layer(4, 1, 0, 0, 8, 0) };
+    let audio = render(&pattern);
+    assert!(audio.chunks(2).all(|frame| frame[1] == audio[1]));
+    assert!(audio.chunks(2).any(|frame| frame[0] != audio[0]));
+}
@@ -0,0 +1,65 @@
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use euclids_square::synthesizer::{BLOCK_LEN, SAMPLE_FREQ};
+use euclids_square::synthesizer::reverb::{Reverb, REVERB_LEN};
+use euclids_square_sim::wav::WavWriter;
+
+// Left and right response to an impulse sent at half of full scale, as the synth renders it
+fn impulse_response(decay: f32, damping: f32, seconds: usize) -> Vec<[f32; 2]> {
+    let mut reverb = Reverb::new(vec![0; REVERB_LEN].leak());
+    reverb.decay = decay;
+    reverb.damping = damping;
+    let num_frames = seconds * SAMPLE_FREQ as usize;
+    let mut sends = vec![[0.; 2]; num_frames];
+    sends[0] = [0.5, 0.5];
+    let mut frames = vec![[0.; 2]; num_frames];
+    for (sends, frames) in sends.chunks(BLOCK_LEN).zip(frames.chunks_mut(BLOCK_LEN)) {
+        reverb.process(sends, frames);
+    }
+    frames
+}
+
+fn energy(frames: &[[f32; 2]]) -> f32 {
+    frames.iter().flatten().map(|v| v * v).sum()
+}
+
+// Energy of the difference of successive samples, which weighs the highs
+fn high_energy(frames: &[[f32; 2]]) -> f32 {
+    frames.windows(2).map(|pair| (pair[1][0] - pair[0][0]).powi(2)).sum()
+}
+
+#[test]
+fn impulse_response_to_wav() {
+    let frames = impulse_response(0.8, 0.5, 3);
+    let samples: Vec<i16> = frames.iter().flatten().map(|&v| (v * 32768.) as i16).collect();
+    let path: PathBuf = [env!("CARGO_TARGET_TMPDIR"), "reverb_impulse_response.wav"].iter().collect();
+    let mut wav = WavWriter::new(BufWriter::new(File::create(&path).unwrap()), SAMPLE_FREQ, 2).unwrap();
+    wav.write_samples(&samples).unwrap();
+    wav.finish().unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 44 + 2 * samples.len() as u64);
+
+    // Silent until the shortest comb, then well below full scale
+    let first = frames.iter().position(|frame| frame != &[0.; 2]).unwrap();
+    assert_eq!(first, 1116);
+    assert!(frames.iter().flatten().all(|v| v.abs() < 0.5));
+
+    // The tail decays a tenth of a second at a time, down to silence
+    let energies: Vec<f32> = frames.chunks(SAMPLE_FREQ as usize / 10).map(energy).collect();
+    assert!(energies.windows(2).all(|pair| pair[1] < pair[0] || pair[0] == 0.), "{:?}", energies);
+    assert_eq!(energies.last(), Some(&0.));
+
+    // The channels are decorrelated
+    let tail = &frames[SAMPLE_FREQ as usize / 10..];
+    let correlation: f32 = tail.iter().map(|frame| frame[0] * frame[1]).sum::<f32>() / (energy(tail) / 2.);
+    assert!(correlation.abs() < 0.5, "{}", correlation);
+}
+
+#[test]
+fn damping_darkens_tail() {
+    let bright = impulse_response(0.8, 0., 1);
+    let dark = impulse_response(0.8, 1., 1);
+    let tail = SAMPLE_FREQ as usize / 2..;
+    let brightness = |frames: &[[f32; 2]]| high_energy(frames) / energy(frames);
+    assert!(brightness(&dark[tail.clone()]) < 0.5 * brightness(&bright[tail]));
+}